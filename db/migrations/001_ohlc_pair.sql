@@ -0,0 +1,24 @@
+\c demo
+
+
+-- Ohlc is stored per trading pair, rows stored before were BTC/USD.
+ALTER TABLE ohlc
+    ADD COLUMN base VARCHAR(16) NOT NULL DEFAULT 'BTC',
+    ADD COLUMN quote VARCHAR(16) NOT NULL DEFAULT 'USD';
+
+ALTER TABLE ohlc
+    ALTER COLUMN base DROP DEFAULT,
+    ALTER COLUMN quote DROP DEFAULT;
+
+-- Amended Ohlc updates stored row, so there can be only one row per candle,
+-- the latest one is kept.
+DELETE FROM ohlc a
+    USING ohlc b
+    WHERE a.base = b.base AND a.quote = b.quote AND a.start = b.start
+        AND a.duration = b.duration AND a.id < b.id;
+
+ALTER TABLE ohlc
+    ALTER COLUMN start SET NOT NULL,
+    ALTER COLUMN duration SET NOT NULL,
+    ADD CONSTRAINT ohlc_base_quote_start_duration_key
+        UNIQUE (base, quote, start, duration);
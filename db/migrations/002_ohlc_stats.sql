@@ -0,0 +1,13 @@
+\c demo
+
+
+-- Gap fill flag and statistics of ticks in Ohlc.
+ALTER TABLE ohlc
+    ADD COLUMN synthetic BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN ticks BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN ts_first TIMESTAMPTZ,
+    ADD COLUMN ts_last TIMESTAMPTZ,
+    ADD COLUMN mean BIGINT,
+    ADD COLUMN twap BIGINT,
+    ADD COLUMN volume BIGINT,
+    ADD COLUMN vwap BIGINT;
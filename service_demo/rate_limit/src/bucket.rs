@@ -0,0 +1,267 @@
+//! Client-side rate limiting that does not depend on endpoint headers.
+//!
+//! TokenBucket implements generic cell rate algorithm (GCRA), which behaves
+//! as token bucket that is refilled continuously, but needs to store only
+//! single timestamp: theoretical arrival time (TAT) of the next request.
+//!
+//! Bucket is shared by all collectors that make requests to the same host,
+//! see Buckets, so that combined request rate does not exceed policy.
+
+
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use crate::{
+    clock::Clock,
+    config::{
+        env_parse,
+        env_parse_millis,
+    },
+};
+
+
+
+/// Local rate limiting policy.
+///
+/// `requests` - number of requests allowed in `window`.
+/// `burst` - number of requests that can be made at once after client has
+/// been idle, it is at least 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketPolicy {
+    pub requests: u32,
+    pub window: Duration,
+    pub burst: u32,
+}
+
+
+
+impl Default for BucketPolicy {
+    /// CoinCap allows 200 requests per minute without API key.
+    fn default() -> Self {
+        Self {
+            requests: 200,
+            window: Duration::from_secs(60),
+            burst: 10,
+        }
+    }
+}
+
+
+
+impl BucketPolicy {
+    /// Load policy from ENV, parameters that are not set keep default values.
+    ///
+    /// `RATE_LIMIT_REQUESTS` - requests per window.
+    /// `RATE_LIMIT_WINDOW_MILLIS` - window duration in milliseconds.
+    /// `RATE_LIMIT_BURST` - burst size.
+    pub fn from_env() -> Result<Self, String> {
+        let mut policy = Self::default();
+
+        env_parse("RATE_LIMIT_REQUESTS", &mut policy.requests)?;
+        env_parse_millis("RATE_LIMIT_WINDOW_MILLIS", &mut policy.window)?;
+        env_parse("RATE_LIMIT_BURST", &mut policy.burst)?;
+
+        if policy.requests == 0 || policy.window.is_zero() || policy.burst == 0 {
+            return Err(concat!("RATE_LIMIT_REQUESTS, RATE_LIMIT_WINDOW_MILLIS",
+                " and RATE_LIMIT_BURST must be positive"
+            ).to_string())
+        }
+
+        Ok(policy)
+    }
+
+
+
+    /// Interval between requests at sustained rate.
+    pub fn emission(&self) -> Duration {
+        self.window / self.requests.max(1)
+    }
+}
+
+
+
+/// Token bucket that can be shared between tasks.
+#[derive(Debug)]
+pub struct TokenBucket {
+    policy: BucketPolicy,
+    tat: Mutex<Option<SystemTime>>,
+}
+
+
+
+impl TokenBucket {
+    pub fn new(policy: BucketPolicy) -> Self {
+        Self {
+            policy,
+            tat: Mutex::new(None),
+        }
+    }
+
+
+
+    pub fn policy(&self) -> &BucketPolicy {
+        &self.policy
+    }
+
+
+
+    /// Reserve request slot at or after `ts`. Returns time when request is
+    /// allowed to be made.
+    ///
+    /// Slot is reserved immediately, so caller must make the request at
+    /// returned time. Slot is never in the past by `clock`, so that requests
+    /// delayed by caller do not use up burst of future requests.
+    pub fn reserve(&self, ts: SystemTime, clock: &dyn Clock) -> SystemTime {
+        let ts = ts.max(clock.now());
+        let emission = self.policy.emission();
+        let tolerance = emission * (self.policy.burst.max(1) - 1);
+
+        // Poisoned lock means that some thread has panicked while holding it,
+        // timestamp itself is still valid.
+        let mut tat = match self.tat.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        let tat_cur = match *tat {
+            Some(tat) if tat > ts => tat,
+            _ => ts,
+        };
+
+        // Request is allowed once TAT is within burst tolerance.
+        let allowed = tat_cur.checked_sub(tolerance).unwrap_or(ts);
+        let ts = if allowed > ts { allowed } else { ts };
+
+        *tat = Some(tat_cur + emission);
+
+        ts
+    }
+}
+
+
+
+/// Token buckets by host, all of them use the same policy.
+#[derive(Debug, Clone)]
+pub struct Buckets {
+    policy: BucketPolicy,
+    buckets: Arc<Mutex<HashMap<String, Arc<TokenBucket>>>>,
+}
+
+
+
+impl Buckets {
+    pub fn new(policy: BucketPolicy) -> Self {
+        Self {
+            policy,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+
+
+    /// Bucket for the host of given URL. If URL can not be parsed, whole URL
+    /// is used as key.
+    pub fn get(&self, url: &str) -> Arc<TokenBucket> {
+        let host = host_key(url);
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        buckets.entry(host)
+            .or_insert_with(|| Arc::new(TokenBucket::new(self.policy)))
+            .clone()
+    }
+}
+
+
+
+/// Key that identifies host of given URL, i.e. `api.coincap.io:443`. If URL
+/// can not be parsed, whole URL is used as key.
+pub fn host_key(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => url.to_string(),
+        },
+        Err(..) => url.to_string(),
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use crate::clock::MockClock;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reserve() {
+        let clock = MockClock::new(at(1000));
+        let reserve = |bucket: &TokenBucket, ts| bucket.reserve(ts, &clock);
+
+        // 10 requests per second, burst 3.
+        let bucket = TokenBucket::new(BucketPolicy {
+            requests: 10,
+            window: Duration::from_secs(1),
+            burst: 3,
+        });
+
+        // Burst is allowed at once, then requests are spaced by 100 ms.
+        let slots: Vec<_> = (0..6).map(|_| reserve(&bucket, at(1000))).collect();
+        assert_eq!(slots, vec![at(1000), at(1000), at(1000), at(1100), at(1200),
+            at(1300)
+        ]);
+
+        // After idle period bucket is full again, but not more than burst.
+        let slots: Vec<_> = (0..4).map(|_| reserve(&bucket, at(5000))).collect();
+        assert_eq!(slots, vec![at(5000), at(5000), at(5000), at(5100)]);
+
+        // Request that is scheduled later anyway is not delayed.
+        assert_eq!(reserve(&bucket, at(9000)), at(9000));
+
+        // Slot is not reserved in the past, clock has moved past TAT.
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(reserve(&bucket, at(9000)), at(21000));
+        assert_eq!(reserve(&bucket, at(9000)), at(21000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buckets_shared_by_host() {
+        let clock = MockClock::new(at(0));
+        let buckets = Buckets::new(BucketPolicy {
+            requests: 1,
+            window: Duration::from_secs(1),
+            burst: 1,
+        });
+
+        let a = buckets.get("https://api.coincap.io/v2/rates/bitcoin");
+        let b = buckets.clone().get("https://api.coincap.io/v2/rates/ethereum");
+        let c = buckets.get("https://api.binance.com/api/v3/ticker/price");
+
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+
+        // Requests of both collectors are spread over the same bucket.
+        assert_eq!(a.reserve(at(0), &clock), at(0));
+        assert_eq!(b.reserve(at(0), &clock), at(1000));
+        assert_eq!(c.reserve(at(0), &clock), at(0));
+    }
+}
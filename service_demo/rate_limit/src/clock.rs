@@ -0,0 +1,148 @@
+//! Source of time for rate limiting and scheduling.
+//!
+//! Two kinds of time are used:
+//! - wall time, that is exchanged with endpoints, i.e. rate limit reset
+//!   timestamps, and used to timestamp data,
+//! - monotonic time, that is used to schedule requests, so that wall clock
+//!   adjustments do not shorten or stretch waiting.
+//!
+//! Both are behind Clock trait, so that SystemClock can be replaced by
+//! MockClock in tests. MockClock follows tokio clock, so with paused tokio
+//! time (`#[tokio::test(start_paused = true)]`) waiting takes no time and is
+//! deterministic.
+
+
+
+use std::{
+    fmt,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+
+
+#[async_trait]
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// Current wall clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Current monotonic time.
+    fn instant(&self) -> Instant;
+
+    /// Wait until given monotonic time, returns immediately if it has already
+    /// passed.
+    async fn sleep_until(&self, deadline: Instant);
+}
+
+
+
+/// Clock that uses system time and tokio timers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+
+
+/// Clock whose wall time starts at given time and then moves together with
+/// tokio clock.
+#[derive(Debug, Clone, Copy)]
+pub struct MockClock {
+    wall_start: SystemTime,
+    instant_start: Instant,
+}
+
+
+
+impl MockClock {
+    pub fn new(wall_start: SystemTime) -> Self {
+        Self {
+            wall_start,
+            instant_start: Instant::now(),
+        }
+    }
+}
+
+
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.wall_start + (Instant::now() - self.instant_start)
+    }
+
+
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+
+
+/// Monotonic deadline that corresponds to wall time `ts`, counted from the
+/// pair of wall and monotonic times taken at the same moment. Deadline is not
+/// earlier than `instant`.
+pub fn deadline(ts: SystemTime, now: SystemTime, instant: Instant) -> Instant {
+    instant + ts.duration_since(now).unwrap_or_default()
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let clock = MockClock::new(start);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+
+        let instant = clock.instant();
+        clock.sleep_until(instant + Duration::from_secs(25)).await;
+        assert_eq!(clock.now(), start + Duration::from_secs(30));
+        assert_eq!(clock.instant(), instant + Duration::from_secs(25));
+
+        // Wall time in the past maps to current monotonic time.
+        let now = clock.now();
+        let instant = clock.instant();
+        assert_eq!(deadline(start, now, instant), instant);
+        assert_eq!(deadline(now + Duration::from_secs(3), now, instant),
+            instant + Duration::from_secs(3)
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Loading of configuration parameters from ENV.
+//!
+//! Policies of this crate and of its users are loaded the same way: parameter
+//! that is not set keeps its default value, parameter that is set must be
+//! valid, otherwise error names parameter and its value.
+
+
+
+use std::{
+    env,
+    str::FromStr,
+    time::Duration,
+};
+
+
+
+/// Parse ENV variable into `value` if it is set, surrounding whitespace is
+/// ignored.
+pub fn env_parse<T: FromStr>(name: &str, value: &mut T) -> Result<(), String> {
+    let Ok(s) = env::var(name) else {
+        return Ok(())
+    };
+
+    *value = s.trim().parse().map_err(|_| format!("{} is not valid: {}", name, s))?;
+
+    Ok(())
+}
+
+
+
+/// Parse ENV variable with duration in milliseconds into `value` if it is
+/// set.
+pub fn env_parse_millis(name: &str, value: &mut Duration) -> Result<(), String> {
+    let mut millis = value.as_millis() as u64;
+    env_parse(name, &mut millis)?;
+
+    *value = Duration::from_millis(millis);
+
+    Ok(())
+}
+
+
+
+/// Read ENV variable, empty value is treated as not set.
+pub fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_env_parse() {
+        // Names are unique to this test, tests run in parallel.
+        env::set_var("CONFIG_TEST_COUNT", " 12 ");
+        env::set_var("CONFIG_TEST_INVALID", "x");
+        env::set_var("CONFIG_TEST_MILLIS", "1500");
+        env::set_var("CONFIG_TEST_EMPTY", " ");
+
+        let mut count = 1u32;
+        env_parse("CONFIG_TEST_COUNT", &mut count).unwrap();
+        assert_eq!(count, 12);
+
+        env_parse("CONFIG_TEST_UNSET", &mut count).unwrap();
+        assert_eq!(count, 12);
+
+        assert_eq!(env_parse("CONFIG_TEST_INVALID", &mut count),
+            Err("CONFIG_TEST_INVALID is not valid: x".to_string())
+        );
+
+        let mut period = Duration::from_secs(1);
+        env_parse_millis("CONFIG_TEST_MILLIS", &mut period).unwrap();
+        assert_eq!(period, Duration::from_millis(1500));
+
+        assert_eq!(env_string("CONFIG_TEST_EMPTY"), None);
+        assert_eq!(env_string("CONFIG_TEST_COUNT"), Some("12".to_string()));
+    }
+}
@@ -0,0 +1,369 @@
+//! Rate limiting response header dialects.
+//!
+//! Endpoints announce rate limits in different ways:
+//! - `X-RateLimit-Limit`, `X-RateLimit-Remaining`, `X-RateLimit-Reset`,
+//!   reset is either Unix timestamp in seconds or milliseconds or seconds
+//!   until reset, some endpoints add `X-RateLimit-Reset-After` in seconds,
+//! - `X-Rate-Limit-Limit`, `X-Rate-Limit-Remaining`, `X-Rate-Limit-Reset`,
+//! - IETF draft `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset`,
+//!   reset is always seconds until reset, limit may contain policies, i.e.
+//!   `100, 100;w=60`,
+//! - IETF draft structured fields `RateLimit: "default";r=50;t=30` and
+//!   `RateLimit-Policy: "default";q=100;w=60`, or older combined form
+//!   `RateLimit: limit=100, remaining=50, reset=30`.
+//!
+//! All dialects are parsed into HeaderLimits, any part of which may be
+//! missing. Reset timestamps are converted from server clock to local clock.
+
+
+
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use reqwest::header::HeaderMap;
+
+use crate::skew::ClockSkew;
+
+
+
+/// Reset values that are at least this large are Unix timestamps in seconds,
+/// smaller are seconds until reset. Nobody uses windows that are decades long.
+const RESET_EPOCH_SECS_MIN: f64 = 1e9;
+
+/// Reset values that are at least this large are Unix timestamps in
+/// milliseconds.
+const RESET_EPOCH_MILLIS_MIN: f64 = 1e12;
+
+
+
+/// Rate limiting information found in response headers.
+///
+/// `limit` - number of requests allowed in window.
+/// `remaining` - number of requests left in current window.
+/// `reset` - time when current window resets.
+/// `window` - window duration, if policy is announced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderLimits {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub reset: Option<SystemTime>,
+    pub window: Option<Duration>,
+}
+
+
+
+impl HeaderLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+
+
+    // Fill fields that are not known yet from other dialect.
+    fn merge(&mut self, other: Self) {
+        self.limit = self.limit.or(other.limit);
+        self.remaining = self.remaining.or(other.remaining);
+        self.reset = self.reset.or(other.reset);
+        self.window = self.window.or(other.window);
+    }
+}
+
+
+
+/// Parse rate limiting information from all known header dialects.
+///
+/// `now` - time when response was received, relative reset values are
+/// counted from it. If multiple dialects are present, IETF headers take
+/// precedence over legacy ones.
+/// `skew` - server clock offset, absolute reset values are converted with it.
+pub fn parse(hm: &HeaderMap, now: SystemTime, skew: &ClockSkew)
+    -> HeaderLimits
+{
+    let mut ret = structured_parse(hm, now);
+
+    ret.merge(HeaderLimits {
+        limit: header_get(hm, "ratelimit-limit").and_then(policy_limit_parse),
+        remaining: header_get(hm, "ratelimit-remaining").and_then(u64_parse),
+        reset: header_get(hm, "ratelimit-reset")
+            .and_then(secs_parse)
+            .and_then(|secs| now.checked_add(secs)),
+        window: header_get(hm, "ratelimit-limit").and_then(policy_window_parse),
+    });
+
+    for prefix in ["x-ratelimit-", "x-rate-limit-"] {
+        let get = |name: &str| header_get(hm, &format!("{}{}", prefix, name));
+
+        let reset = get("reset-after")
+            .and_then(secs_parse)
+            .and_then(|secs| now.checked_add(secs))
+            .or_else(|| get("reset").and_then(|val| reset_parse(val, now, skew)));
+
+        ret.merge(HeaderLimits {
+            limit: get("limit").and_then(policy_limit_parse),
+            remaining: get("remaining").and_then(u64_parse),
+            reset,
+            window: get("limit").and_then(policy_window_parse),
+        });
+    }
+
+    ret
+}
+
+
+
+fn header_get<'a>(hm: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    hm.get(name)?.to_str().ok().map(str::trim)
+}
+
+
+
+fn u64_parse(val: &str) -> Option<u64> {
+    val.trim().parse().ok()
+}
+
+
+
+// Non-negative number of seconds, fractions are allowed.
+fn secs_parse(val: &str) -> Option<Duration> {
+    let secs: f64 = val.trim().parse().ok()?;
+
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+
+
+// Legacy reset value that may be timestamp or delay, see RESET_EPOCH_SECS_MIN.
+fn reset_parse(val: &str, now: SystemTime, skew: &ClockSkew)
+    -> Option<SystemTime>
+{
+    let secs: f64 = val.trim().parse().ok()?;
+
+    if secs >= RESET_EPOCH_MILLIS_MIN {
+        UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(secs / 1000.0).ok()?)
+            .map(|ts| skew.to_local(ts))
+    }
+    else if secs >= RESET_EPOCH_SECS_MIN {
+        UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(secs).ok()?)
+            .map(|ts| skew.to_local(ts))
+    }
+    else {
+        now.checked_add(Duration::try_from_secs_f64(secs).ok()?)
+    }
+}
+
+
+
+// Limit value may be followed by policies, i.e. "100, 100;w=60", first
+// number is effective limit.
+fn policy_limit_parse(val: &str) -> Option<u64> {
+    let first = val.split(',').next()?;
+
+    u64_parse(first.split(';').next()?)
+}
+
+
+
+// Window of the first policy that matches limit, i.e. "100, 100;w=60".
+fn policy_window_parse(val: &str) -> Option<Duration> {
+    let limit = policy_limit_parse(val)?;
+
+    val.split(',').skip(1)
+        .map(item_parse)
+        .find(|(item, _)| u64_parse(item) == Some(limit))
+        .and_then(|(_, params)| param_get(&params, "w"))
+        .and_then(u64_parse)
+        .map(Duration::from_secs)
+}
+
+
+
+// Split structured field list member into item and parameters, i.e.
+// `"default";r=50;t=30` into `"default"` and [("r", "50"), ("t", "30")].
+fn item_parse(member: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = member.split(';').map(str::trim);
+    let item = parts.next().unwrap_or("");
+
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, val)| (key.trim(), val.trim()))
+        .collect();
+
+    (item, params)
+}
+
+
+
+fn param_get<'a>(params: &[(&'a str, &'a str)], key: &str) -> Option<&'a str> {
+    params.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| *v)
+}
+
+
+
+// Structured `RateLimit` and `RateLimit-Policy` fields. Only the first
+// policy is used, endpoints list the most restrictive or the default one
+// first.
+fn structured_parse(hm: &HeaderMap, now: SystemTime) -> HeaderLimits {
+    let mut ret = HeaderLimits::default();
+
+    if let Some(val) = header_get(hm, "ratelimit") {
+        let members: Vec<_> = val.split(',').map(item_parse).collect();
+
+        // Older combined form uses dictionary members, i.e. "remaining=50".
+        let dict: Vec<(&str, &str)> = members.iter()
+            .filter_map(|(item, _)| item.split_once('='))
+            .map(|(key, val)| (key.trim(), val.trim()))
+            .collect();
+
+        if !dict.is_empty() {
+            ret.limit = param_get(&dict, "limit").and_then(u64_parse);
+            ret.remaining = param_get(&dict, "remaining").and_then(u64_parse);
+            ret.reset = param_get(&dict, "reset")
+                .and_then(secs_parse)
+                .and_then(|secs| now.checked_add(secs));
+        }
+        else if let Some((_, params)) = members.first() {
+            ret.remaining = param_get(params, "r").and_then(u64_parse);
+            ret.reset = param_get(params, "t")
+                .and_then(secs_parse)
+                .and_then(|secs| now.checked_add(secs));
+        }
+    }
+
+    if let Some(val) = header_get(hm, "ratelimit-policy") {
+        if let Some(member) = val.split(',').next() {
+            let (item, params) = item_parse(member);
+
+            // Older form has limit as item, i.e. "100;w=60".
+            ret.limit = ret.limit
+                .or_else(|| param_get(&params, "q").and_then(u64_parse))
+                .or_else(|| u64_parse(item));
+            ret.window = param_get(&params, "w")
+                .and_then(u64_parse)
+                .map(Duration::from_secs);
+        }
+    }
+
+    ret
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::{
+        HeaderName,
+        HeaderValue,
+    };
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut hm = HeaderMap::new();
+
+        for (name, val) in pairs {
+            hm.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(val).unwrap()
+            );
+        }
+
+        hm
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_legacy() {
+        let now = at(1_700_000_000);
+        let skew = ClockSkew::default();
+
+        // Epoch seconds.
+        let hm = headers(&[("X-RateLimit-Limit", "200"),
+            ("X-RateLimit-Remaining", "150"), ("X-RateLimit-Reset", "1700000060")
+        ]);
+        assert_eq!(parse(&hm, now, &skew), HeaderLimits {
+            limit: Some(200),
+            remaining: Some(150),
+            reset: Some(at(1_700_000_060)),
+            window: None,
+        });
+
+        // Epoch milliseconds and other prefix.
+        let hm = headers(&[("x-rate-limit-remaining", "3"),
+            ("x-rate-limit-reset", "1700000060000")
+        ]);
+        assert_eq!(parse(&hm, now, &skew).reset, Some(at(1_700_000_060)));
+        assert_eq!(parse(&hm, now, &skew).remaining, Some(3));
+        assert_eq!(parse(&hm, now, &skew).limit, None);
+
+        // Seconds until reset.
+        let hm = headers(&[("X-RateLimit-Reset", "30")]);
+        assert_eq!(parse(&hm, now, &skew).reset, Some(at(1_700_000_030)));
+
+        let hm = headers(&[("X-RateLimit-Reset-After", "1.5")]);
+        assert_eq!(parse(&hm, now, &skew).reset,
+            Some(now + Duration::from_millis(1500))
+        );
+
+        // Server clock is 5 s ahead, only timestamps are converted.
+        let skew = ClockSkew::default();
+        skew.sample(now, now, now + Duration::from_secs(5));
+
+        let hm = headers(&[("X-RateLimit-Reset", "1700000060")]);
+        assert_eq!(parse(&hm, now, &skew).reset, Some(at(1_700_000_055)));
+
+        let hm = headers(&[("X-RateLimit-Reset", "30")]);
+        assert_eq!(parse(&hm, now, &skew).reset, Some(at(1_700_000_030)));
+    }
+
+    #[test]
+    fn test_ietf() {
+        let now = at(1_700_000_000);
+        let skew = ClockSkew::default();
+
+        let hm = headers(&[("RateLimit-Limit", "100, 100;w=60, 1000;w=3600"),
+            ("RateLimit-Remaining", "40"), ("RateLimit-Reset", "20")
+        ]);
+        assert_eq!(parse(&hm, now, &skew), HeaderLimits {
+            limit: Some(100),
+            remaining: Some(40),
+            reset: Some(at(1_700_000_020)),
+            window: Some(Duration::from_secs(60)),
+        });
+
+        let hm = headers(&[("RateLimit", r#""default";r=50;t=30"#),
+            ("RateLimit-Policy", r#""default";q=100;w=60"#)
+        ]);
+        assert_eq!(parse(&hm, now, &skew), HeaderLimits {
+            limit: Some(100),
+            remaining: Some(50),
+            reset: Some(at(1_700_000_030)),
+            window: Some(Duration::from_secs(60)),
+        });
+
+        let hm = headers(&[("RateLimit", "limit=10, remaining=5, reset=7"),
+            ("RateLimit-Policy", "10;w=15")
+        ]);
+        assert_eq!(parse(&hm, now, &skew), HeaderLimits {
+            limit: Some(10),
+            remaining: Some(5),
+            reset: Some(at(1_700_000_007)),
+            window: Some(Duration::from_secs(15)),
+        });
+
+        // IETF headers take precedence, legacy ones fill the gaps.
+        let hm = headers(&[("RateLimit-Remaining", "1"),
+            ("X-RateLimit-Remaining", "9"), ("X-RateLimit-Limit", "10")
+        ]);
+        assert_eq!(parse(&hm, now, &skew).remaining, Some(1));
+        assert_eq!(parse(&hm, now, &skew).limit, Some(10));
+
+        assert!(parse(&headers(&[("RateLimit-Reset", "soon")]), now, &skew).is_empty());
+        assert!(parse(&HeaderMap::new(), now, &skew).is_empty());
+    }
+}
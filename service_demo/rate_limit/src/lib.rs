@@ -0,0 +1,655 @@
+//! Rate limiting of HTTP requests to remote endpoints.
+//!
+//! Rate limits announced by endpoint are read from response headers, see
+//! headers.rs for supported dialects. Local policy, see bucket.rs, is applied
+//! regardless of headers, request is made only when both allow it.
+//!
+//! RateLimit can be driven manually, i.e. by polling loop that decides when
+//! to make next request, or used transparently as reqwest middleware, see
+//! middleware.rs. Time is taken from Clock, see clock.rs, so that behaviour
+//! can be tested without waiting. Intervals between own requests are
+//! measured with monotonic time, wall time is used only for values that are
+//! exchanged with endpoint. Absolute times announced by endpoint are
+//! converted from server clock to local clock, see skew.rs. Policies are
+//! loaded from ENV with helpers of config.rs, service uses them as well.
+//!
+//! # Status 429 "Too many requests"
+//! If endpoint returns status 429, next request is not made before time given
+//! by Retry-After header, either in delta-seconds or HTTP-date form. If header
+//! is not returned, exponential backoff with jitter is used, it grows while
+//! 429 responses repeat and is reset by any other response.
+//!
+pub mod headers;
+pub mod bucket;
+pub mod clock;
+pub mod config;
+pub mod middleware;
+pub mod skew;
+
+
+
+use std::{
+    cell::Cell,
+    sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use tokio::time::Instant;
+
+use reqwest::{
+    header::{
+        HeaderMap,
+        RETRY_AFTER,
+    },
+    Response,
+    StatusCode,
+};
+
+use bucket::TokenBucket;
+use clock::{
+    Clock,
+    SystemClock,
+};
+use skew::ClockSkew;
+
+
+
+/// Rate limiting information returned in response to request.
+///
+/// `start` - wall time when request was made.
+/// `started` - monotonic time when request was made.
+#[derive(Debug, Clone)]
+pub struct RateLimitInfo {
+    limit: u64,
+    remaining: u64,
+    start: SystemTime,
+    started: Instant,
+    reset: SystemTime,
+}
+
+
+
+/// Default backoff after status 429 without Retry-After header.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+
+
+/// Rate limiting state.
+///
+/// `retry_after` - time before which next request must not be made, it is
+/// set after status 429.
+/// `too_many` - number of consecutive 429 responses.
+/// `jitter` - random generator state for backoff jitter, seeded on first
+/// use.
+/// `bucket` - local rate limiting policy, it may be shared with other
+/// RateLimit instances that make requests to the same host.
+/// `warned` - set once missing rate limiting information is reported.
+/// `clock` - source of time.
+/// `started` - monotonic time when current request was started.
+/// `skew` - server clock offset, it is estimated from Date headers.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    cur: Option<RateLimitInfo>,
+    prev: Option<RateLimitInfo>,
+    retry_after: Option<SystemTime>,
+    too_many: u32,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    jitter: u64,
+    bucket: Option<Arc<TokenBucket>>,
+    warned: Cell<bool>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    skew: Arc<ClockSkew>,
+}
+
+
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            cur: None,
+            prev: None,
+            retry_after: None,
+            too_many: 0,
+            backoff_min: BACKOFF_MIN,
+            backoff_max: BACKOFF_MAX,
+            jitter: 0,
+            bucket: None,
+            warned: Cell::new(false),
+            clock: Arc::new(SystemClock),
+            started: Instant::now(),
+            skew: Arc::new(ClockSkew::default()),
+        }
+    }
+}
+
+
+
+/// Parse Retry-After header value, that is either delay in seconds or
+/// HTTP-date. Delay is counted from `now`, date is converted from server
+/// clock to local clock.
+pub fn retry_after_parse(value: &str, now: SystemTime, skew: &ClockSkew)
+    -> Option<SystemTime>
+{
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return now.checked_add(Duration::from_secs(secs))
+    }
+
+    httpdate::parse_http_date(value).ok().map(|ts| skew.to_local(ts))
+}
+
+
+
+impl RateLimit {
+    /// Start rate limiting current request.
+    ///
+    /// This method must be called on each request that must be rate limited.
+    pub fn start(&mut self) {
+        self.prev = self.cur.clone();
+        self.cur = None;
+        self.started = self.clock.instant();
+    }
+
+
+
+    /// Reset rate limiting information.
+    pub fn reset(&mut self) {
+        self.cur = None;
+        self.prev = None;
+        self.retry_after = None;
+        self.too_many = 0;
+    }
+
+
+
+    /// Set backoff range that is used after status 429 without Retry-After
+    /// header.
+    pub fn backoff_set(&mut self, backoff_min: Duration, backoff_max: Duration) {
+        self.backoff_min = backoff_min;
+        self.backoff_max = backoff_max.max(backoff_min);
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+
+
+    /// Set server clock offset estimate, it may be shared with other
+    /// RateLimit instances that make requests to the same server.
+    pub fn skew_set(&mut self, skew: Arc<ClockSkew>) {
+        self.skew = skew;
+    }
+
+
+
+    /// Server clock offset estimate.
+    pub fn skew(&self) -> &Arc<ClockSkew> {
+        &self.skew
+    }
+
+
+
+    /// Set local rate limiting policy that is applied regardless of
+    /// information returned by endpoint.
+    pub fn bucket_set(&mut self, bucket: Arc<TokenBucket>) {
+        self.bucket = Some(bucket);
+    }
+
+
+
+    /// Time before which next request must not be made, if endpoint has
+    /// asked to slow down.
+    pub fn retry_after(&self) -> Option<SystemTime> {
+        self.retry_after
+    }
+
+
+
+    /// Update RateLimit struct information from HTTP response status and
+    /// headers.
+    ///
+    /// If API endpoint returns rate limiting HTTP headers, RateLimit uses
+    /// information from it to update it's internal state. In further calls
+    /// to adjust next request this information is taken into consideration.
+    ///
+    /// If current rate limit is already calculated by HTTP status 429 "Too many
+    /// requests", then this method does not update internal state unless HTTP
+    /// headers contain more restrictive rate limiting.
+    pub fn update_from_response(&mut self, start: &SystemTime, r: &Response) {
+        self.update_from_headers(start, r.status(), r.headers())
+    }
+
+
+
+    /// Update RateLimit from HTTP status and headers, see
+    /// update_from_response.
+    ///
+    /// Relative values in headers are counted from the time when this method
+    /// is called.
+    pub fn update_from_headers(&mut self, start: &SystemTime, status: StatusCode,
+        hm: &HeaderMap
+    )
+    {
+        let now = self.clock.now();
+
+        // Estimate is updated first, so that it is used for times in this
+        // response already.
+        self.skew.sample_date(*start, now, hm);
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            self.too_many = self.too_many.saturating_add(1);
+
+            let retry_after = hm.get(RETRY_AFTER)
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| retry_after_parse(val, now, &self.skew));
+
+            self.retry_after = Some(match retry_after {
+                Some(retry_after) => retry_after,
+                None => now + self.backoff_next(),
+            });
+        }
+        else {
+            self.too_many = 0;
+            self.retry_after = None;
+        }
+
+        //
+        // Overwrite rate limiting info from rate limiting headers, if that is
+        // available.
+        //
+
+        let limits = headers::parse(hm, now, &self.skew);
+
+        // Remaining requests are needed to pace requests, if they are not
+        // announced, limit is used and request rate is evenly spread over
+        // window.
+        let Some(remaining) = limits.remaining.or(limits.limit) else {
+            return
+        };
+
+        // Reset time is needed to know when window ends, if it is not
+        // announced, we assume that whole window has just started.
+        let reset = limits.reset
+            .or_else(|| limits.window.and_then(|w| start.checked_add(w)));
+
+        let Some(reset) = reset else {
+            return
+        };
+
+        let rl_info = RateLimitInfo {
+            limit: limits.limit.unwrap_or(remaining),
+            remaining,
+            start: *start,
+            started: self.started,
+            reset,
+        };
+
+        // Overwrite current only if headers contain more-strict rate limiting.
+        if let Some(cur) = &mut self.cur {
+            if cur.reset < rl_info.reset {
+                cur.reset = rl_info.reset;
+            }
+
+            if cur.remaining > rl_info.remaining {
+                cur.remaining = rl_info.remaining;
+            }
+
+            if cur.limit > rl_info.limit {
+                cur.limit = rl_info.limit;
+            }
+
+            return
+        }
+
+        self.cur = Some(rl_info);
+    }
+
+
+
+    /// Adjust time for next request based on rate limit.
+    ///
+    /// Request is scheduled at the time when it is allowed by endpoint rate
+    /// limiting information, Retry-After and local policy. If local policy is
+    /// set, request slot is reserved in it, so request must be made at
+    /// adjusted time.
+    pub fn ts_next_req_adjust(&self, ts_next_req: &mut SystemTime) {
+        if self.cur.is_some() {
+            self.ts_next_req_adjust_limits(ts_next_req);
+        }
+        else if self.retry_after.is_none() && self.bucket.is_none()
+            && !self.warned.replace(true)
+        {
+            eprintln!(concat!("WARNING: endpoint does not return rate limiting",
+                " information and local policy is not set, requests are not",
+                " rate limited."
+            ));
+        }
+
+        // Endpoint has told explicitly when to retry, rate limiting
+        // information can only postpone it further.
+        if let Some(retry_after) = self.retry_after {
+            if *ts_next_req < retry_after {
+                *ts_next_req = retry_after;
+            }
+        }
+
+        if let Some(ref bucket) = self.bucket {
+            *ts_next_req = bucket.reserve(*ts_next_req, self.clock.as_ref());
+        }
+    }
+
+
+
+    // Backoff for current number of consecutive 429 responses.
+    //
+    // Backoff is doubled on each response, half of it is random, so that
+    // multiple clients that were limited at the same time do not retry at
+    // the same time again.
+    fn backoff_next(&mut self) -> Duration {
+        let exp = self.too_many.saturating_sub(1).min(31);
+        let backoff = self.backoff_min.saturating_mul(1 << exp)
+            .min(self.backoff_max);
+
+        if self.jitter == 0 {
+            self.jitter = self.clock.now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1) | 1;
+        }
+
+        // Xorshift64.
+        self.jitter ^= self.jitter << 13;
+        self.jitter ^= self.jitter >> 7;
+        self.jitter ^= self.jitter << 17;
+
+        let half = backoff / 2;
+        let jitter_ms = self.jitter % (half.as_millis() as u64 + 1);
+
+        half + Duration::from_millis(jitter_ms)
+    }
+
+
+
+    // Adjust time for next request based on rate limiting headers.
+    fn ts_next_req_adjust_limits(&self, ts_next_req: &mut SystemTime) {
+        let Some(ref prev) = self.prev else {
+            return self.ts_next_req_adjust_prev_none(ts_next_req)
+        };
+
+        let Some(ref cur) = self.cur else {
+            return
+        };
+
+        // If request is scheduled after rate limiting window reset, it will not
+        // exceed rate limit imposed by API endpoint.
+        // This code path should always execute for appropriateley configured
+        // system.
+        // This works correctly only if system clocks are synchronized well
+        // enough.
+        if *ts_next_req >= cur.reset {
+            return
+        }
+
+        // If rate-limit reached, wait till next window.
+        if cur.remaining < 1 {
+            *ts_next_req = cur.reset;
+            return
+        }
+
+        // Measure interval between our requests. Use this instead of absolute
+        // clock value to minimize impact on un-synced clocks between our system
+        // and API endpoint. Monotonic time can not go backwards.
+        let request_interval = cur.started.duration_since(prev.started);
+
+        let Ok(win_duration) = cur.reset.duration_since(cur.start) else {
+            // API endpoint should never return timestamp that is before request
+            // start unless clock on any of involved systems is out of sync. In
+            // such an occurance, we ignore dynamic rate limiting calculations
+            // and use configured ones.
+            eprintln!(concat!("WARNING: Host and enpoint clocks are too far",
+                " out of sync, requested rate limiting policy might not be",
+                " honored properly. Possible solutions to this:\n",
+                "1) Ensure that system clock is correct and stable,\n",
+                "2) Increase request period (it is possible that remote",
+                " endpoint can not hadnle that may requests),\n",
+                "3) Check that in case if remote endpoint is load balanced",
+                " between multiple nodes, that clocks between all nodes are",
+                " synchronized."
+            ));
+
+            return
+        };
+
+        let d = win_duration.as_millis();
+        let allowed_duration_ms = d / cur.remaining as u128;
+
+        // We can confidently drop unused bits without loosing any meaningful
+        // resolution.
+        let rl_duration = Duration::from_millis(allowed_duration_ms as u64);
+
+        // If system is making requests on bigger intervals than rate limit
+        // requires to do, we can use that duration.
+        if request_interval > rl_duration {
+            return
+        }
+
+        // Since we must have duration of seconds between requests, we must
+        // adjust so that next request is number of seconds since the previous.
+        *ts_next_req = cur.start + rl_duration;
+    }
+
+
+
+    // Adjust request timestamp without any information about previous request
+    // time and rate limits.
+    //
+    // This function should be called only for the first request made.
+    #[inline]
+    fn ts_next_req_adjust_prev_none(&self, ts_next_req: &mut SystemTime) {
+        let Some(ref cur) = self.cur else {
+            return
+        };
+
+        if *ts_next_req >= cur.reset {
+            return
+        }
+
+        // Since this is the first request, and we are allowed to make more
+        // requests, there is no need to rate-limit next.
+        // We trust that system configuration good enough for service to work
+        // properly.
+        if cur.remaining > 0 {
+            return
+        }
+
+        // Being here is not a good start. Because if local system time is out
+        // of sync with remote clock, we are using time to sleep based on
+        // remote clock. But there is nothing much we can do if system time
+        // is not set correctly.
+
+        eprintln!(concat!("WARNING: API endpoint call rate limit was hit on",
+            " first request"
+        ));
+
+        if cur.reset > *ts_next_req {
+            *ts_next_req = cur.reset;
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use clock::MockClock;
+
+    fn headers(retry_after: Option<&str>) -> HeaderMap {
+        let mut hm = HeaderMap::new();
+
+        if let Some(retry_after) = retry_after {
+            hm.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        }
+
+        hm
+    }
+
+    #[test]
+    fn test_retry_after_parse() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let skew = ClockSkew::default();
+
+        assert_eq!(retry_after_parse("120", now, &skew),
+            Some(UNIX_EPOCH + Duration::from_secs(1120))
+        );
+        assert_eq!(retry_after_parse("Sun, 06 Nov 1994 08:49:37 GMT", now, &skew),
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        assert_eq!(retry_after_parse("Sunday, 06-Nov-94 08:49:37 GMT", now, &skew),
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        assert_eq!(retry_after_parse("Sun Nov  6 08:49:37 1994", now, &skew),
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        assert_eq!(retry_after_parse("-1", now, &skew), None);
+        assert_eq!(retry_after_parse("soon", now, &skew), None);
+    }
+
+    #[test]
+    fn test_retry_after_adjust() {
+        let mut rl = RateLimit::default();
+        let start = SystemTime::now();
+
+        rl.start();
+        rl.update_from_headers(&start, StatusCode::TOO_MANY_REQUESTS,
+            &headers(Some("30"))
+        );
+
+        let mut ts_next_req = start + Duration::from_secs(1);
+        rl.ts_next_req_adjust(&mut ts_next_req);
+        assert!(ts_next_req >= start + Duration::from_secs(30));
+
+        // Request that is scheduled later anyway is not changed.
+        let later = start + Duration::from_secs(100);
+        let mut ts_next_req = later;
+        rl.ts_next_req_adjust(&mut ts_next_req);
+        assert_eq!(ts_next_req, later);
+
+        // Successful response clears limit.
+        rl.start();
+        rl.update_from_headers(&start, StatusCode::OK, &headers(None));
+        assert_eq!(rl.retry_after(), None);
+    }
+
+    /// Policy without remaining and reset spreads requests over window.
+    /// Interval between own requests is measured with monotonic clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_update_partial() {
+        let mut hm = HeaderMap::new();
+        hm.insert("ratelimit-policy", HeaderValue::from_static("10;w=60"));
+
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH
+            + Duration::from_secs(1_700_000_000)
+        ));
+
+        let mut rl = RateLimit::default();
+        rl.clock_set(clock.clone());
+
+        // The first request is not delayed, the second is made too soon
+        // after it, the third is made rarely enough.
+        for (interval, delay) in [(0, 100), (1, 6000), (10, 100)] {
+            tokio::time::advance(Duration::from_secs(interval)).await;
+
+            let start = clock.now();
+            rl.start();
+            rl.update_from_headers(&start, StatusCode::OK, &hm);
+
+            let mut ts_next_req = start + Duration::from_millis(100);
+            rl.ts_next_req_adjust(&mut ts_next_req);
+            assert_eq!(ts_next_req, start + Duration::from_millis(delay));
+        }
+    }
+
+    /// Stricter of endpoint and local policy is applied.
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket_combined() {
+        let bucket = Arc::new(TokenBucket::new(bucket::BucketPolicy {
+            requests: 1,
+            window: Duration::from_secs(2),
+            burst: 1,
+        }));
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH
+            + Duration::from_secs(1_700_000_000)
+        ));
+
+        let mut rl = RateLimit::default();
+        rl.clock_set(clock.clone());
+        rl.bucket_set(bucket.clone());
+
+        // No headers, local policy only.
+        let start = clock.now();
+        let mut ts_next_req = start;
+        rl.ts_next_req_adjust(&mut ts_next_req);
+        assert_eq!(ts_next_req, start);
+
+        let mut ts_next_req = start;
+        rl.ts_next_req_adjust(&mut ts_next_req);
+        assert_eq!(ts_next_req, start + Duration::from_secs(2));
+
+        // Endpoint asks to wait longer than local policy.
+        rl.start();
+        rl.update_from_headers(&start, StatusCode::TOO_MANY_REQUESTS,
+            &headers(Some("10"))
+        );
+
+        let mut ts_next_req = start;
+        rl.ts_next_req_adjust(&mut ts_next_req);
+        assert!(ts_next_req >= start + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut rl = RateLimit::default();
+        rl.backoff_set(Duration::from_secs(1), Duration::from_secs(8));
+
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            let start = SystemTime::now();
+
+            rl.start();
+            rl.update_from_headers(&start, StatusCode::TOO_MANY_REQUESTS,
+                &headers(None)
+            );
+
+            let delay = rl.retry_after().unwrap().duration_since(start).unwrap();
+            delays.push(delay);
+        }
+
+        // Backoff is 1, 2, 4, 8, 8, 8 seconds, half of it is jitter.
+        for (delay, backoff) in delays.iter().zip([1, 2, 4, 8, 8, 8]) {
+            let backoff = Duration::from_secs(backoff);
+
+            assert!(*delay >= backoff / 2, "{:?} {:?}", delay, backoff);
+            assert!(*delay <= backoff + Duration::from_millis(100),
+                "{:?} {:?}", delay, backoff
+            );
+        }
+
+        rl.start();
+        let start = SystemTime::now();
+        rl.update_from_headers(&start, StatusCode::OK, &headers(None));
+        assert_eq!(rl.too_many, 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! Reqwest middleware that delays requests according to learned rate limits.
+//!
+//! Each host has own RateLimit, that learns limits from responses, and, if
+//! local policy is set, shares TokenBucket with other middleware instances.
+//! Requests to the same host are made one at a time, so that each of them is
+//! scheduled with limits returned in response to the previous one. Use client
+//! timeouts, otherwise request that hangs holds back following requests to
+//! the same host.
+//!
+//! ```ignore
+//! let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+//!     .with(RateLimitMiddleware::new())
+//!     .build();
+//! ```
+
+
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use http::Extensions;
+use reqwest::{
+    Request,
+    Response,
+};
+use reqwest_middleware::{
+    Middleware,
+    Next,
+    Result,
+};
+
+use crate::{
+    RateLimit,
+    bucket::{
+        self,
+        Buckets,
+    },
+    clock::{
+        self,
+        Clock,
+        SystemClock,
+    },
+};
+
+
+
+/// Rate limiting middleware.
+///
+/// `clock` - source of time, shared with RateLimit of every host.
+/// `buckets` - local rate limiting policy, if it is not set, only limits
+/// returned by endpoints are honored.
+/// `backoff` - backoff range after status 429 without Retry-After header.
+/// `hosts` - rate limiting state by host, see bucket::host_key.
+pub struct RateLimitMiddleware {
+    clock: Arc<dyn Clock>,
+    buckets: Option<Buckets>,
+    backoff: Option<(Duration, Duration)>,
+    hosts: Mutex<HashMap<String, Arc<tokio::sync::Mutex<RateLimit>>>>,
+}
+
+
+
+impl Default for RateLimitMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+impl RateLimitMiddleware {
+    pub fn new() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            buckets: None,
+            backoff: None,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+
+
+    /// Set local rate limiting policy. Buckets may be shared with other
+    /// middleware instances or manually driven RateLimit, so that all of them
+    /// together do not exceed policy.
+    pub fn buckets_set(&mut self, buckets: Buckets) {
+        self.buckets = Some(buckets);
+    }
+
+
+
+    /// Set backoff range that is used after status 429 without Retry-After
+    /// header.
+    pub fn backoff_set(&mut self, backoff_min: Duration, backoff_max: Duration) {
+        self.backoff = Some((backoff_min, backoff_max));
+    }
+
+
+
+    // Rate limiting state of the host of given URL, created on first request.
+    fn host_get(&self, url: &str) -> Arc<tokio::sync::Mutex<RateLimit>> {
+        let mut hosts = match self.hosts.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        hosts.entry(bucket::host_key(url))
+            .or_insert_with(|| {
+                let mut rate_limit = RateLimit::default();
+                rate_limit.clock_set(self.clock.clone());
+
+                if let Some(ref buckets) = self.buckets {
+                    rate_limit.bucket_set(buckets.get(url));
+                }
+
+                if let Some((backoff_min, backoff_max)) = self.backoff {
+                    rate_limit.backoff_set(backoff_min, backoff_max);
+                }
+
+                Arc::new(tokio::sync::Mutex::new(rate_limit))
+            })
+            .clone()
+    }
+}
+
+
+
+#[async_trait]
+impl Middleware for RateLimitMiddleware {
+    async fn handle(&self, req: Request, extensions: &mut Extensions,
+        next: Next<'_>
+    )
+        -> Result<Response>
+    {
+        let host = self.host_get(req.url().as_str());
+        let mut rate_limit = host.lock().await;
+
+        let now = self.clock.now();
+        let instant = self.clock.instant();
+
+        let mut ts_next_req = now;
+        rate_limit.ts_next_req_adjust(&mut ts_next_req);
+        self.clock.sleep_until(clock::deadline(ts_next_req, now, instant)).await;
+
+        rate_limit.start();
+        let start = self.clock.now();
+
+        let response = next.run(req, extensions).await?;
+
+        rate_limit.update_from_headers(&start, response.status(),
+            response.headers()
+        );
+
+        Ok(response)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{
+        SystemTime,
+        UNIX_EPOCH,
+    };
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use reqwest_middleware::ClientBuilder;
+    use crate::{
+        bucket::BucketPolicy,
+        clock::MockClock,
+    };
+
+    // Stand-in endpoint that answers each connection with next of given
+    // status lines and headers. Returns endpoint URL and times, by mock clock,
+    // when requests arrived.
+    async fn serve(clock: MockClock, responses: Vec<&'static str>)
+        -> (String, JoinHandle<Vec<SystemTime>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut arrivals = Vec::new();
+
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0u8; 4096];
+                assert!(stream.read(&mut buf).await.unwrap() > 0);
+                arrivals.push(clock.now());
+
+                let response = format!(concat!("{}\r\n",
+                    "Content-Length: 0\r\n",
+                    "Connection: close\r\n\r\n"
+                ), response);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+
+            arrivals
+        });
+
+        (url, server)
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Limits learned from headers and Retry-After delay following requests.
+    #[tokio::test(start_paused = true)]
+    async fn test_learned_limits() {
+        let clock = MockClock::new(at(1_700_000_000));
+
+        let (url, server) = serve(clock, vec![
+            concat!("HTTP/1.1 200 OK\r\n",
+                "X-RateLimit-Limit: 2\r\n",
+                "X-RateLimit-Remaining: 0\r\n",
+                "X-RateLimit-Reset: 30"
+            ),
+            concat!("HTTP/1.1 429 Too Many Requests\r\n",
+                "Retry-After: 5"
+            ),
+            "HTTP/1.1 200 OK",
+        ]).await;
+
+        let mut middleware = RateLimitMiddleware::new();
+        middleware.clock_set(Arc::new(clock));
+
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        for status in [200, 429, 200] {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status().as_u16(), status);
+        }
+
+        // Window is exhausted by the first request, so second waits for
+        // reset, third is made once Retry-After has passed.
+        assert_eq!(server.await.unwrap(),
+            vec![at(1_700_000_000), at(1_700_000_030), at(1_700_000_035)]
+        );
+    }
+
+    /// Middleware instances that share buckets do not exceed local policy
+    /// together.
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_buckets() {
+        let clock = MockClock::new(at(1_700_000_000));
+
+        let (url, server) = serve(clock, vec!["HTTP/1.1 200 OK"; 3]).await;
+
+        let buckets = Buckets::new(BucketPolicy {
+            requests: 1,
+            window: Duration::from_secs(10),
+            burst: 1,
+        });
+
+        let clients: Vec<_> = (0..2).map(|_| {
+            let mut middleware = RateLimitMiddleware::new();
+            middleware.clock_set(Arc::new(clock));
+            middleware.buckets_set(buckets.clone());
+
+            ClientBuilder::new(reqwest::Client::new())
+                .with(middleware)
+                .build()
+        }).collect();
+
+        for client in [&clients[0], &clients[1], &clients[0]] {
+            client.get(&url).send().await.unwrap();
+        }
+
+        assert_eq!(server.await.unwrap(),
+            vec![at(1_700_000_000), at(1_700_000_010), at(1_700_000_020)]
+        );
+    }
+}
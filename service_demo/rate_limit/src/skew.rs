@@ -0,0 +1,212 @@
+//! Estimation of server clock offset against local clock.
+//!
+//! Timestamps returned by endpoint, i.e. rate limit reset or data timestamps,
+//! are read by server clock. If it differs from local clock, they must be
+//! converted before they are compared with local time.
+//!
+//! Offset is estimated NTP-style: server has read its clock somewhere between
+//! the moment request was sent and response received, we assume the middle,
+//! i.e. local send time plus half of round trip time. Date header has second
+//! resolution, so its value is moved to the middle of the second as well.
+//! Single sample is imprecise, so samples are smoothed with exponentially
+//! weighted moving average.
+
+
+
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{
+            AtomicI64,
+            Ordering,
+        },
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use reqwest::header::{
+    DATE,
+    HeaderMap,
+};
+
+
+
+/// Weight of new sample in moving average.
+const ALPHA: f64 = 0.125;
+
+/// Samples with longer round trip time are too imprecise to be used.
+const RTT_MAX: Duration = Duration::from_secs(10);
+
+
+
+#[derive(Debug, Default)]
+struct SkewState {
+    offset: f64,
+    samples: u64,
+}
+
+
+
+/// Estimated server clock offset, it can be shared by all clients of the same
+/// server.
+///
+/// `offset_millis` - offset in milliseconds, server clock minus local clock,
+/// it can be read by metrics without locking.
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    state: Mutex<SkewState>,
+    offset_millis: Arc<AtomicI64>,
+}
+
+
+
+impl ClockSkew {
+    /// Add sample of server time `server`, that was read while request sent
+    /// at `sent` was processed and response received at `received`.
+    pub fn sample(&self, sent: SystemTime, received: SystemTime,
+        server: SystemTime
+    )
+    {
+        let Ok(rtt) = received.duration_since(sent) else {
+            return
+        };
+
+        if rtt > RTT_MAX {
+            return
+        }
+
+        let offset = secs(server) - (secs(sent) + rtt.as_secs_f64() / 2.0);
+
+        let mut state = match self.state.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        state.offset = match state.samples {
+            0 => offset,
+            _ => state.offset + ALPHA * (offset - state.offset),
+        };
+        state.samples += 1;
+
+        self.offset_millis.store((state.offset * 1000.0).round() as i64,
+            Ordering::Relaxed
+        );
+    }
+
+
+
+    /// Add sample from Date header of response, if it is present.
+    pub fn sample_date(&self, sent: SystemTime, received: SystemTime,
+        hm: &HeaderMap
+    )
+    {
+        let date = hm.get(DATE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| httpdate::parse_http_date(val).ok());
+
+        if let Some(date) = date {
+            self.sample(sent, received, date + Duration::from_millis(500));
+        }
+    }
+
+
+
+    /// Estimated offset in milliseconds, server clock minus local clock. It
+    /// is 0 until first sample is added.
+    pub fn offset_millis(&self) -> i64 {
+        self.offset_millis.load(Ordering::Relaxed)
+    }
+
+
+
+    /// Offset in milliseconds that can be registered as metric.
+    pub fn metric(&self) -> Arc<AtomicI64> {
+        self.offset_millis.clone()
+    }
+
+
+
+    /// Convert server time to local time.
+    pub fn to_local(&self, server: SystemTime) -> SystemTime {
+        let offset = self.offset_millis();
+        let d = Duration::from_millis(offset.unsigned_abs());
+
+        let local = if offset >= 0 {
+            server.checked_sub(d)
+        }
+        else {
+            server.checked_add(d)
+        };
+
+        local.unwrap_or(server)
+    }
+}
+
+
+
+// Signed seconds since Unix epoch.
+fn secs(ts: SystemTime) -> f64 {
+    match ts.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_sample() {
+        let skew = ClockSkew::default();
+        assert_eq!(skew.to_local(at(5000)), at(5000));
+
+        // Server is 2 s ahead, it read its clock in the middle of 200 ms
+        // round trip.
+        skew.sample(at(1_000_000), at(1_000_200), at(1_002_100));
+        assert_eq!(skew.offset_millis(), 2000);
+        assert_eq!(skew.to_local(at(1_010_000)), at(1_008_000));
+
+        // Outlier moves estimate only by fraction.
+        skew.sample(at(1_001_000), at(1_001_000), at(1_011_000));
+        assert_eq!(skew.offset_millis(), 3000);
+
+        // Samples with broken or too long round trip are ignored.
+        skew.sample(at(1_002_000), at(1_001_000), at(1_000_000));
+        skew.sample(at(1_002_000), at(1_013_000), at(1_000_000));
+        assert_eq!(skew.metric().load(Ordering::Relaxed), 3000);
+
+        // Server behind local clock.
+        let skew = ClockSkew::default();
+        skew.sample(at(1_000_000), at(1_000_000), at(999_250));
+        assert_eq!(skew.offset_millis(), -750);
+        assert_eq!(skew.to_local(at(2_000_000)), at(2_000_750));
+    }
+
+    /// Date header is read as the middle of given second.
+    #[test]
+    fn test_sample_date() {
+        let mut hm = HeaderMap::new();
+        hm.insert(DATE, HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        let sent = at(784_111_777_000) - Duration::from_millis(3100);
+        let skew = ClockSkew::default();
+        skew.sample_date(sent, sent + Duration::from_millis(200), &hm);
+        assert_eq!(skew.offset_millis(), 3500);
+
+        skew.sample_date(sent, sent, &HeaderMap::new());
+        assert_eq!(skew.offset_millis(), 3500);
+    }
+}
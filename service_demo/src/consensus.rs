@@ -0,0 +1,575 @@
+//! Consensus of rates polled from several sources.
+//!
+//! Every source sends rates into its own channel. For each pair the latest
+//! rate of every source is kept, whenever any source sends a rate, rates that
+//! are not stale are combined into single consensus rate, that is sent to
+//! OhlcCalc. So a single bad tick from one source does not end up in Ohlc:
+//! - rates that deviate from median of all fresh rates by more than
+//!   `max_deviation_bps` are rejected,
+//! - rates that were not updated for `stale` are not used at all,
+//! - consensus is made only if at least `min_sources` rates are accepted.
+//!
+//! Sources that contributed or were rejected are counted by metrics, i.e.
+//! `consensus_contributions_total{source="binance",pair="BTC/USD"}`, stale
+//! rate is counted once, when it becomes stale. Names of sources that
+//! contributed are kept in `sources` of every consensus rate.
+
+
+
+use std::{
+    collections::HashMap,
+    env,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::Duration,
+};
+
+use futures_util::{
+    StreamExt,
+    stream,
+};
+use tokio::{
+    sync::mpsc,
+    time::Instant,
+};
+
+use rate_limit::{
+    clock::{
+        Clock,
+        SystemClock,
+    },
+    config::env_parse,
+};
+
+use crate::{
+    shared_state::SharedState,
+    price::Price,
+    price_info::PriceInfo,
+    symbol::Symbol,
+};
+
+
+
+/// How fresh rates are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Method {
+    /// Median of accepted rates.
+    #[default]
+    Median,
+    /// Mean of accepted rates weighted by source weights, i.e. by share of
+    /// trading volume of the source.
+    Weighted,
+    /// Mean of accepted rates without `trim_percent` of the lowest and the
+    /// highest rates.
+    TrimmedMean,
+}
+
+
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "median" => Ok(Self::Median),
+            "weighted" => Ok(Self::Weighted),
+            "trimmed_mean" => Ok(Self::TrimmedMean),
+            _ => Err(format!("unknown consensus method: {}", s)),
+        }
+    }
+}
+
+
+
+/// Consensus configuration.
+///
+/// `max_deviation_bps` - rates that deviate from median by more basis
+/// points (1/100 of percent) are rejected.
+/// `stale` - rates that were not updated for that long are not used.
+/// `min_sources` - minimal number of accepted rates.
+/// `trim_percent` - share of rates dropped from each end by TrimmedMean.
+/// `quote_aliases` - quotes that are treated as the same currency, i.e.
+/// USDT as USD, so that rates of different sources can be combined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusPolicy {
+    pub method: Method,
+    pub max_deviation_bps: u32,
+    pub stale: Duration,
+    pub min_sources: usize,
+    pub trim_percent: u32,
+    pub quote_aliases: Vec<(Symbol, Symbol)>,
+}
+
+
+
+impl Default for ConsensusPolicy {
+    fn default() -> Self {
+        Self {
+            method: Method::default(),
+            max_deviation_bps: 100,
+            stale: Duration::from_secs(10),
+            min_sources: 1,
+            trim_percent: 25,
+            quote_aliases: Vec::new(),
+        }
+    }
+}
+
+
+
+impl ConsensusPolicy {
+    /// Load policy from ENV, parameters that are not set keep default values.
+    ///
+    /// `CONSENSUS_METHOD` - median, weighted or trimmed_mean.
+    /// `CONSENSUS_MAX_DEVIATION_BPS` - maximal deviation from median.
+    /// `CONSENSUS_STALE_MILLIS` - age of rate after which it is not used.
+    /// `CONSENSUS_MIN_SOURCES` - minimal number of accepted rates.
+    /// `CONSENSUS_TRIM_PERCENT` - share of rates trimmed from each end.
+    /// `CONSENSUS_QUOTE_ALIASES` - comma separated `ALIAS:QUOTE` pairs, i.e.
+    /// `USDT:USD`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut policy = Self::default();
+        let mut stale_millis = policy.stale.as_millis() as u64;
+
+        env_parse("CONSENSUS_METHOD", &mut policy.method)?;
+        env_parse("CONSENSUS_MAX_DEVIATION_BPS", &mut policy.max_deviation_bps)?;
+        env_parse("CONSENSUS_STALE_MILLIS", &mut stale_millis)?;
+        env_parse("CONSENSUS_MIN_SOURCES", &mut policy.min_sources)?;
+        env_parse("CONSENSUS_TRIM_PERCENT", &mut policy.trim_percent)?;
+
+        if policy.min_sources == 0 {
+            return Err("CONSENSUS_MIN_SOURCES must be positive".to_string())
+        }
+
+        if policy.trim_percent >= 50 {
+            return Err("CONSENSUS_TRIM_PERCENT must be less than 50".to_string())
+        }
+
+        policy.stale = Duration::from_millis(stale_millis);
+
+        let aliases = env::var("CONSENSUS_QUOTE_ALIASES").unwrap_or_default();
+        for alias in aliases.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            let pair = alias.split_once(':').and_then(|(alias, quote)| {
+                Some((Symbol::new(alias).ok()?, Symbol::new(quote).ok()?))
+            });
+
+            let Some(pair) = pair else {
+                return Err(format!("CONSENSUS_QUOTE_ALIASES is not valid: {}",
+                    alias
+                ))
+            };
+
+            policy.quote_aliases.push(pair);
+        }
+
+        Ok(policy)
+    }
+
+
+
+    fn quote(&self, quote: Symbol) -> Symbol {
+        self.quote_aliases.iter()
+            .find(|(alias, _)| *alias == quote)
+            .map_or(quote, |(_, quote)| *quote)
+    }
+}
+
+
+
+/// Latest rate of single source for single pair.
+///
+/// `source` - index of source in order of Consensus::input_add calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub source: usize,
+    pub rate: Price,
+    pub weight: u64,
+}
+
+
+
+/// Combine rates of different sources, returns consensus rate and sources
+/// whose rates were accepted. None is returned if less than `min_sources`
+/// rates are accepted.
+pub fn aggregate(candidates: &[Candidate], policy: &ConsensusPolicy)
+    -> Option<(Price, Vec<usize>)>
+{
+    // Rates are compared at common scale.
+    let scale = candidates.iter().map(|c| c.rate.scale()).max()?;
+    let values: Vec<(u128, &Candidate)> = candidates.iter()
+        .filter_map(|c| Some((c.rate.rescale(scale).ok()?.value() as u128, c)))
+        .collect();
+
+    let mut all: Vec<u128> = values.iter().map(|(v, _)| *v).collect();
+    let mid = median(&mut all)?;
+
+    let accepted: Vec<(u128, &Candidate)> = values.into_iter()
+        .filter(|(v, _)| {
+            v.abs_diff(mid) * 10_000 <= policy.max_deviation_bps as u128 * mid
+        })
+        .collect();
+
+    if accepted.is_empty() || accepted.len() < policy.min_sources {
+        return None
+    }
+
+    let mut rates: Vec<u128> = accepted.iter().map(|(v, _)| *v).collect();
+
+    let rate = match policy.method {
+        Method::Median => median(&mut rates)?,
+
+        Method::Weighted => {
+            let weights: u128 = accepted.iter()
+                .map(|(_, c)| c.weight as u128)
+                .sum();
+            let sum: u128 = accepted.iter()
+                .map(|(v, c)| v * c.weight as u128)
+                .sum();
+
+            div_round(sum, weights)?
+        }
+
+        Method::TrimmedMean => {
+            rates.sort_unstable();
+
+            let trim = rates.len() * policy.trim_percent as usize / 100;
+            let trimmed = &rates[trim..rates.len() - trim];
+
+            div_round(trimmed.iter().sum(), trimmed.len() as u128)?
+        }
+    };
+
+    let rate = Price::new(u64::try_from(rate).ok()?, scale).ok()?;
+
+    Some((rate, accepted.iter().map(|(_, c)| c.source).collect()))
+}
+
+
+
+// Median, mean of two middle values if there is even number of them.
+fn median(values: &mut [u128]) -> Option<u128> {
+    values.sort_unstable();
+
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(values[mid]),
+        _ => div_round(values[mid - 1] + values[mid], 2),
+    }
+}
+
+
+
+// Division rounded half up.
+fn div_round(num: u128, div: u128) -> Option<u128> {
+    if div == 0 {
+        return None
+    }
+
+    Some((num + div / 2) / div)
+}
+
+
+
+/// Combines rates of several sources into consensus rates.
+///
+/// `inputs` - name, weight and receiving end of channel of every source.
+/// `tx` - consensus rates are sent there.
+/// `clock` - source of time, used to find stale rates.
+pub struct Consensus {
+    inputs: Vec<(String, u64, mpsc::Receiver<PriceInfo>)>,
+    tx: mpsc::Sender<PriceInfo>,
+    policy: ConsensusPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+
+
+impl Consensus {
+    pub fn new(policy: ConsensusPolicy, tx: mpsc::Sender<PriceInfo>) -> Self {
+        Self {
+            inputs: Vec::new(),
+            tx,
+            policy,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+
+
+    /// Add source with given name and weight, that is used by Weighted
+    /// method. Returns sender that source must send its rates to.
+    pub fn input_add(&mut self, name: &str, weight: u64)
+        -> mpsc::Sender<PriceInfo>
+    {
+        let (tx, rx) = mpsc::channel(200);
+        self.inputs.push((name.to_string(), weight, rx));
+
+        tx
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+
+
+/// Latest rate of single source.
+///
+/// `stale` - whether rate has already been found stale.
+#[derive(Debug, Clone, Copy)]
+struct Quote {
+    rate: Price,
+    timestamp: u64,
+    received: Instant,
+    stale: bool,
+}
+
+
+
+pub async fn main(consensus: Consensus, shared_state: Arc<SharedState>) {
+    let Consensus { inputs, tx, policy, clock } = consensus;
+
+    let mut sources = Vec::with_capacity(inputs.len());
+    let mut streams = Vec::with_capacity(inputs.len());
+
+    for (i, (name, weight, rx)) in inputs.into_iter().enumerate() {
+        sources.push((name, weight));
+
+        let rates = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|info| (info, rx))
+        });
+        streams.push(rates.map(move |info| (i, info)).boxed());
+    }
+
+    // Ends once all sources have closed their channels.
+    let mut rates = stream::select_all(streams);
+
+    let metric = |name: &str, labels: String| {
+        shared_state.metrics.get(&format!("{}{{{}}}", name, labels))
+            .fetch_add(1, Ordering::Relaxed);
+    };
+
+    // Latest rate of every source by pair.
+    let mut quotes: HashMap<(Symbol, Symbol), Vec<Option<Quote>>> =
+        HashMap::new();
+
+    while let Some((source, info)) = rates.next().await {
+        let Some(rate) = info.rate else { continue };
+
+        let pair = (info.base, policy.quote(info.quote));
+        let label = |i: usize| {
+            format!("source=\"{}\",pair=\"{}/{}\"", sources[i].0, pair.0, pair.1)
+        };
+        let now = clock.instant();
+
+        let slots = quotes.entry(pair)
+            .or_insert_with(|| vec![None; sources.len()]);
+        slots[source] = Some(Quote {
+            rate,
+            timestamp: info.timestamp,
+            received: now,
+            stale: false,
+        });
+
+        let mut candidates = Vec::with_capacity(slots.len());
+        for (i, quote) in slots.iter_mut().enumerate() {
+            let Some(quote) = quote else { continue };
+
+            if now - quote.received > policy.stale {
+                if !quote.stale {
+                    quote.stale = true;
+                    metric("consensus_rejected_total",
+                        label(i) + ",reason=\"stale\""
+                    );
+                }
+
+                continue
+            }
+
+            candidates.push(Candidate {
+                source: i,
+                rate: quote.rate,
+                weight: sources[i].1,
+            });
+        }
+
+        let Some((rate, accepted)) = aggregate(&candidates, &policy) else {
+            metric("consensus_no_quorum_total",
+                format!("pair=\"{}/{}\"", pair.0, pair.1)
+            );
+            continue
+        };
+
+        for c in candidates.iter().filter(|c| !accepted.contains(&c.source)) {
+            metric("consensus_rejected_total",
+                label(c.source) + ",reason=\"deviation\""
+            );
+        }
+
+        // Nothing has changed if rate that has just arrived is rejected.
+        if !accepted.contains(&source) {
+            continue
+        }
+
+        let mut timestamp = 0;
+        for i in &accepted {
+            metric("consensus_contributions_total", label(*i));
+
+            if let Some(quote) = slots[*i] {
+                timestamp = timestamp.max(quote.timestamp);
+            }
+        }
+
+        let mut info = PriceInfo::new(timestamp, pair.0, pair.1, Some(rate));
+        info.sources = accepted.iter().map(|i| sources[*i].0.clone()).collect();
+
+        if tx.try_send(info).is_err() {
+            eprintln!(concat!("ERROR: backend can not process incomming data",
+                " fast enough, dropping consensus rate."
+            ));
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use rate_limit::clock::MockClock;
+
+    fn candidates(rates: &[(u64, u64)]) -> Vec<Candidate> {
+        rates.iter().enumerate()
+            .map(|(source, (rate, weight))| Candidate {
+                source,
+                rate: Price::new(*rate, 2).unwrap(),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let policy = |method| ConsensusPolicy {
+            method,
+            ..ConsensusPolicy::default()
+        };
+
+        // Third rate deviates by more than 1% and is rejected.
+        let c = candidates(&[(10000, 1), (10050, 3), (12000, 1), (10020, 1)]);
+        let aggregated = |method| aggregate(&c, &policy(method))
+            .map(|(rate, accepted)| (rate.value(), accepted));
+
+        assert_eq!(aggregated(Method::Median), Some((10020, vec![0, 1, 3])));
+        assert_eq!(aggregated(Method::Weighted), Some((10034, vec![0, 1, 3])));
+
+        let c = candidates(&[(10000, 1), (10010, 1), (10020, 1), (10090, 1)]);
+        assert_eq!(aggregate(&c, &policy(Method::TrimmedMean)).unwrap().0.value(),
+            10015
+        );
+
+        // Rates with different scales are compared at common scale.
+        let mut c = candidates(&[(10000, 1)]);
+        c.push(Candidate {
+            source: 1,
+            rate: Price::new(100_010, 3).unwrap(),
+            weight: 1,
+        });
+        let (rate, _) = aggregate(&c, &policy(Method::Median)).unwrap();
+        assert_eq!((rate.value(), rate.scale()), (100_005, 3));
+
+        // Two rates that disagree can not be told apart, no quorum.
+        let c = candidates(&[(10000, 1), (11000, 1)]);
+        assert_eq!(aggregate(&c, &policy(Method::Median)), None);
+
+        let c = candidates(&[(10000, 1)]);
+        let policy = ConsensusPolicy {
+            min_sources: 2,
+            ..ConsensusPolicy::default()
+        };
+        assert_eq!(aggregate(&c, &policy), None);
+    }
+
+    /// Outliers and stale rates are not used, quotes are aliased.
+    #[tokio::test(start_paused = true)]
+    async fn test_consensus() {
+        let btc = Symbol::new("BTC").unwrap();
+        let usdt = Symbol::new("USDT").unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut consensus = Consensus::new(ConsensusPolicy {
+            quote_aliases: vec![(usdt, Symbol::USD)],
+            ..ConsensusPolicy::default()
+        }, tx);
+        consensus.clock_set(Arc::new(MockClock::new(UNIX_EPOCH)));
+
+        let inputs: Vec<_> = ["coincap", "binance", "simulated"].iter()
+            .map(|name| consensus.input_add(name, 1))
+            .collect();
+
+        let state = Arc::new(SharedState::default());
+        let consensus_h = tokio::spawn(main(consensus, state.clone()));
+
+        let send = |source: usize, ts, quote, rate| {
+            let info = PriceInfo::new(ts, btc, quote,
+                Some(Price::new(rate, 2).unwrap())
+            );
+            inputs[source].try_send(info).unwrap();
+        };
+        let recv = |rx: &mut mpsc::Receiver<PriceInfo>| {
+            let info = rx.try_recv().unwrap();
+            (info.timestamp, info.quote, info.rate.unwrap().value())
+        };
+
+        send(0, 100, Symbol::USD, 10000);
+        tokio::task::yield_now().await;
+        assert_eq!(recv(&mut rx), (100, Symbol::USD, 10000));
+
+        send(1, 101, usdt, 10020);
+        tokio::task::yield_now().await;
+        let info = rx.try_recv().unwrap();
+        assert_eq!((info.timestamp, info.quote, info.rate.unwrap().value()),
+            (101, Symbol::USD, 10010)
+        );
+        assert_eq!(info.sources, vec!["coincap", "binance"]);
+
+        // Outlier does not produce consensus rate.
+        send(2, 102, Symbol::USD, 20000);
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+
+        // Once first two rates are stale, outlier is the only fresh rate.
+        tokio::time::advance(Duration::from_secs(11)).await;
+        send(2, 113, Symbol::USD, 20010);
+        tokio::task::yield_now().await;
+        assert_eq!(recv(&mut rx), (113, Symbol::USD, 20010));
+
+        // Stale rates are counted only once.
+        send(2, 114, Symbol::USD, 20020);
+        tokio::task::yield_now().await;
+        assert_eq!(recv(&mut rx), (114, Symbol::USD, 20020));
+
+        drop(inputs);
+        consensus_h.await.unwrap();
+
+        let metric = |name: &str| state.metrics.get(name).load(Ordering::Relaxed);
+        assert_eq!(metric(concat!("consensus_rejected_total{source=\"simulated\",",
+            "pair=\"BTC/USD\",reason=\"deviation\"}"
+        )), 1);
+        assert_eq!(metric(concat!("consensus_rejected_total{source=\"coincap\",",
+            "pair=\"BTC/USD\",reason=\"stale\"}"
+        )), 1);
+        assert_eq!(metric(concat!("consensus_contributions_total{",
+            "source=\"binance\",pair=\"BTC/USD\"}"
+        )), 1);
+    }
+}
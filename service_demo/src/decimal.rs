@@ -0,0 +1,528 @@
+//! Decimal string parser for rates returned by upstream APIs.
+//!
+//! Rates are usually returned as strings, i.e. "67123.4512345678901234", so
+//! that JSON number precission is not lost. Parser keeps all significant
+//! digits, so conversion into Price is exact unless rounding is requested
+//! explicitly.
+//!
+//! Supported format: optional sign, digits with optional decimal point and
+//! optional exponent, i.e. "42", "-0.5", "+.25", "1.5e-3", "2E4".
+
+
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use crate::price::{
+    Price,
+    PriceError,
+    SCALE_MAX,
+};
+
+
+
+/// Largest absolute exponent that is accepted. Anything bigger can not be
+/// represented as Price anyway and would only waste memory.
+const EXPONENT_MAX: i64 = 1_000_000;
+
+
+
+/// Rounding mode used when decimal has more digits than target scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// Discard extra digits, round towards zero.
+    Down,
+    /// Round away from zero if any discarded digit is not zero.
+    Up,
+    /// Round to nearest, ties away from zero.
+    #[default]
+    HalfUp,
+    /// Round to nearest, ties to even (banker's rounding).
+    HalfEven,
+}
+
+
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "down" => Ok(Self::Down),
+            "up" => Ok(Self::Up),
+            "half_up" => Ok(Self::HalfUp),
+            "half_even" => Ok(Self::HalfEven),
+            _ => Err(format!("unknown rounding mode: {}", s)),
+        }
+    }
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimalError {
+    Empty,
+    /// Unexpected character at given byte position.
+    InvalidChar(usize),
+    /// Number contains no digits, i.e. "." or "-e5".
+    NoDigits,
+    ExponentInvalid,
+    /// Negative value can not be converted into Price.
+    Negative,
+    Price(PriceError),
+}
+
+
+
+impl fmt::Display for DecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "decimal string is empty"),
+            Self::InvalidChar(pos) => write!(f, "invalid character at {}", pos),
+            Self::NoDigits => write!(f, "decimal string contains no digits"),
+            Self::ExponentInvalid => write!(f, "exponent is invalid or too large"),
+            Self::Negative => write!(f, "negative value is not a valid price"),
+            Self::Price(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+
+
+impl From<PriceError> for DecimalError {
+    fn from(e: PriceError) -> Self {
+        Self::Price(e)
+    }
+}
+
+
+
+/// Parsed decimal number: (-1)^negative * digits * 10^exponent.
+///
+/// `digits` - significant digits without leading and trailing zeros, each
+/// element is in 0..=9. Empty for zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decimal {
+    negative: bool,
+    digits: Vec<u8>,
+    exponent: i64,
+}
+
+
+
+impl Decimal {
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+
+
+    /// Number of decimal places needed to represent value exactly.
+    pub fn scale(&self) -> u64 {
+        if self.exponent < 0 {
+            self.exponent.unsigned_abs()
+        }
+        else {
+            0
+        }
+    }
+
+
+
+    /// Convert decimal into Price with given scale, rounding if decimal has
+    /// more decimal places than scale.
+    pub fn to_price(&self, scale: u8, rounding: Rounding)
+        -> Result<Price, DecimalError>
+    {
+        let (value, inexact) = self.scaled(scale, rounding)?;
+
+        if self.negative && (value != 0 || inexact) {
+            return Err(DecimalError::Negative)
+        }
+
+        Ok(Price::new(value, scale)?)
+    }
+
+
+
+    /// Convert decimal into Price keeping as many decimal places as possible,
+    /// but not more than `scale_max`.
+    ///
+    /// If value at such scale does not fit into Price, scale is reduced until
+    /// it fits.
+    pub fn to_price_fit(&self, scale_max: u8, rounding: Rounding)
+        -> Result<Price, DecimalError>
+    {
+        let mut scale = self.scale().min(scale_max as u64) as u8;
+
+        loop {
+            match self.to_price(scale, rounding) {
+                Err(DecimalError::Price(PriceError::Overflow)) if scale > 0 => {
+                    scale -= 1;
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+
+
+    // Absolute value multiplied by 10^scale and rounded, together with flag
+    // that is set if any non-zero digit was discarded.
+    fn scaled(&self, scale: u8, rounding: Rounding)
+        -> Result<(u64, bool), DecimalError>
+    {
+        if self.digits.is_empty() {
+            return Ok((0, false))
+        }
+
+        let overflow = DecimalError::Price(PriceError::Overflow);
+        let exp = self.exponent + scale as i64;
+        let len = self.digits.len() as i64;
+
+        // Number of leading digits that stay in integer part.
+        let keep = len + exp.min(0);
+
+        let mut value: u64 = 0;
+        for digit in &self.digits[..keep.max(0) as usize] {
+            value = value.checked_mul(10)
+                .and_then(|v| v.checked_add(*digit as u64))
+                .ok_or(overflow)?;
+        }
+
+        if exp > 0 {
+            let mul = u32::try_from(exp).ok()
+                .and_then(|exp| 10u64.checked_pow(exp))
+                .ok_or(overflow)?;
+
+            value = value.checked_mul(mul).ok_or(overflow)?;
+
+            return Ok((value, false))
+        }
+
+        if keep >= len {
+            return Ok((value, false))
+        }
+
+        // Digits are normalized, so there is at least one non-zero digit
+        // discarded. If there are virtual zeros between decimal point and
+        // first digit, then first discarded digit is zero.
+        let (first, rest_nonzero) = if keep < 0 {
+            (0, true)
+        }
+        else {
+            let keep = keep as usize;
+            let rest_nonzero = self.digits[keep + 1..].iter().any(|d| *d != 0);
+
+            (self.digits[keep], rest_nonzero)
+        };
+
+        let round_up = match rounding {
+            Rounding::Down => false,
+            Rounding::Up => true,
+            Rounding::HalfUp => first >= 5,
+            Rounding::HalfEven => {
+                first > 5 || (first == 5 && (rest_nonzero || value % 2 == 1))
+            }
+        };
+
+        if round_up {
+            value = value.checked_add(1).ok_or(overflow)?;
+        }
+
+        Ok((value, true))
+    }
+}
+
+
+
+/// Parse rate string into Price, keeping as many decimal places as fit into
+/// Price.
+pub fn price_parse(s: &str, rounding: Rounding) -> Result<Price, DecimalError> {
+    s.parse::<Decimal>()?.to_price_fit(SCALE_MAX, rounding)
+}
+
+
+
+/// Parse traded volume string into Price with given scale.
+///
+/// Volume is summed into Ohlc at fixed scale, so it is rounded half up to
+/// that scale regardless of rounding configured for rates.
+pub fn volume_parse(s: &str, scale: u8) -> Result<Price, DecimalError> {
+    s.parse::<Decimal>()?.to_price(scale, Rounding::HalfUp)
+}
+
+
+
+impl FromStr for Decimal {
+    type Err = DecimalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(DecimalError::Empty)
+        }
+
+        let bytes = s.as_bytes();
+        let mut pos = 0;
+
+        let negative = match bytes[0] {
+            b'-' => { pos += 1; true }
+            b'+' => { pos += 1; false }
+            _ => false,
+        };
+
+        let mut digits: Vec<u8> = Vec::with_capacity(bytes.len());
+        let mut exponent: i64 = 0;
+        let mut point = false;
+
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b @ b'0'..=b'9' => {
+                    // Skip leading zeros, they are not significant.
+                    if !digits.is_empty() || b != b'0' {
+                        digits.push(b - b'0');
+                    }
+
+                    if point {
+                        exponent -= 1;
+                    }
+                }
+                b'.' if !point => point = true,
+                b'e' | b'E' => break,
+                _ => return Err(DecimalError::InvalidChar(pos)),
+            }
+
+            pos += 1;
+        }
+
+        // At least one digit must be present before exponent.
+        let mantissa_start = if negative || bytes[0] == b'+' { 1 } else { 0 };
+        if !bytes[mantissa_start..pos].iter().any(u8::is_ascii_digit) {
+            return Err(DecimalError::NoDigits)
+        }
+
+        if pos < bytes.len() {
+            // Skip exponent marker.
+            let exp_str = &s[pos + 1..];
+            let exp_valid = exp_str.trim_start_matches(['+', '-'])
+                .bytes()
+                .all(|b| b.is_ascii_digit());
+
+            if exp_str.len() > 2 + 20 || !exp_valid {
+                return Err(DecimalError::ExponentInvalid)
+            }
+
+            let exp: i64 = exp_str.parse()
+                .map_err(|_| DecimalError::ExponentInvalid)?;
+
+            if exp.abs() > EXPONENT_MAX {
+                return Err(DecimalError::ExponentInvalid)
+            }
+
+            exponent += exp;
+        }
+
+        // Trailing zeros are moved into exponent.
+        while let Some(0) = digits.last() {
+            digits.pop();
+            exponent += 1;
+        }
+
+        if digits.is_empty() {
+            return Ok(Self {
+                negative: false,
+                digits,
+                exponent: 0,
+            })
+        }
+
+        Ok(Self {
+            negative,
+            digits,
+            exponent,
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn price(s: &str, scale: u8, rounding: Rounding)
+        -> Result<u64, DecimalError>
+    {
+        let decimal: Decimal = s.parse()?;
+
+        decimal.to_price(scale, rounding).map(|p| p.value())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(price("67123.4567", 4, Rounding::Down), Ok(671234567));
+        assert_eq!(price("42", 2, Rounding::Down), Ok(4200));
+        assert_eq!(price("0.5", 2, Rounding::Down), Ok(50));
+        assert_eq!(price("+.25", 2, Rounding::Down), Ok(25));
+        assert_eq!(price("7.", 0, Rounding::Down), Ok(7));
+        assert_eq!(price("1.5e-3", 4, Rounding::Down), Ok(15));
+        assert_eq!(price("2E4", 0, Rounding::Down), Ok(20000));
+        assert_eq!(price("0.00012345678901234567890123", 18, Rounding::Down),
+            Ok(123456789012345)
+        );
+        assert_eq!(price("-0", 2, Rounding::Down), Ok(0));
+        assert_eq!(price(" 000123.4500 ", 2, Rounding::Down), Ok(12345));
+
+        assert_eq!(price("", 0, Rounding::Down), Err(DecimalError::Empty));
+        assert_eq!(price(".", 0, Rounding::Down), Err(DecimalError::NoDigits));
+        assert_eq!(price("-e5", 0, Rounding::Down), Err(DecimalError::NoDigits));
+        assert_eq!(price("1.2.3", 0, Rounding::Down),
+            Err(DecimalError::InvalidChar(3))
+        );
+        assert_eq!(price("1e", 0, Rounding::Down),
+            Err(DecimalError::ExponentInvalid)
+        );
+        assert_eq!(price("1e9999999", 0, Rounding::Down),
+            Err(DecimalError::ExponentInvalid)
+        );
+        assert_eq!(price("-1.5", 1, Rounding::Down), Err(DecimalError::Negative));
+        assert_eq!(price("18446744073709551616", 0, Rounding::Down),
+            Err(DecimalError::Price(PriceError::Overflow))
+        );
+    }
+
+    #[test]
+    fn test_rounding() {
+        let cases = [
+            // value, Down, Up, HalfUp, HalfEven
+            ("1.24", 12, 13, 12, 12),
+            ("1.25", 12, 13, 13, 12),
+            ("1.35", 13, 14, 14, 14),
+            ("1.251", 12, 13, 13, 13),
+            ("1.2", 12, 12, 12, 12),
+            ("0.001", 0, 1, 0, 0),
+            ("0.05", 0, 1, 1, 0),
+        ];
+
+        for (s, down, up, half_up, half_even) in cases {
+            assert_eq!(price(s, 1, Rounding::Down), Ok(down), "{}", s);
+            assert_eq!(price(s, 1, Rounding::Up), Ok(up), "{}", s);
+            assert_eq!(price(s, 1, Rounding::HalfUp), Ok(half_up), "{}", s);
+            assert_eq!(price(s, 1, Rounding::HalfEven), Ok(half_even), "{}", s);
+        }
+
+        // Negative value that rounds to zero is still negative.
+        assert_eq!(price("-0.001", 1, Rounding::Down), Err(DecimalError::Negative));
+    }
+
+    #[test]
+    fn test_to_price_fit() {
+        let decimal: Decimal = "67123.45678901234567890123".parse().unwrap();
+        let price = decimal.to_price_fit(19, Rounding::HalfUp).unwrap();
+
+        assert_eq!(price.scale(), 14);
+        assert_eq!(price.value(), 6712345678901234568);
+
+        let decimal: Decimal = "1.5".parse().unwrap();
+        let price = decimal.to_price_fit(19, Rounding::HalfUp).unwrap();
+        assert_eq!((price.value(), price.scale()), (15, 1));
+    }
+
+    #[test]
+    fn test_volume_parse() {
+        let volume = volume_parse("0.123456789", 8).unwrap();
+        assert_eq!((volume.value(), volume.scale()), (12345679, 8));
+
+        let volume = volume_parse("2", 8).unwrap();
+        assert_eq!((volume.value(), volume.scale()), (200000000, 8));
+
+        assert_eq!(volume_parse("-1", 8), Err(DecimalError::Negative));
+    }
+
+    proptest! {
+        /// Any price formatted as string is parsed back exactly.
+        #[test]
+        fn prop_roundtrip(value: u64, scale in 0u8..=19) {
+            let p = Price::new(value, scale).unwrap();
+            let decimal: Decimal = p.to_string().parse().unwrap();
+
+            prop_assert_eq!(decimal.to_price(scale, Rounding::Down), Ok(p));
+            prop_assert_eq!(decimal.to_price_fit(19, Rounding::Down), Ok(p));
+        }
+
+        /// Exponent notation gives the same value as shifted decimal point.
+        #[test]
+        fn prop_exponent(mantissa in 0u64..1_000_000_000, exp in -12i64..=6) {
+            let decimal: Decimal = format!("{}e{}", mantissa, exp).parse().unwrap();
+            let expected = mantissa as u128 * 10u128.pow((exp + 12) as u32);
+
+            prop_assert_eq!(
+                decimal.to_price(12, Rounding::Down).map(|p| p.value() as u128),
+                u64::try_from(expected)
+                    .map(|v| v as u128)
+                    .map_err(|_| DecimalError::Price(PriceError::Overflow))
+            );
+        }
+
+        /// Leading and trailing zeros and explicit plus sign do not change
+        /// value.
+        #[test]
+        fn prop_zeros(int in "[0-9]{1,10}", frac in "[0-9]{0,10}",
+            lead in 0usize..5, trail in 0usize..5
+        ) {
+            let plain: Decimal = format!("{}.{}", int, frac).parse().unwrap();
+            let padded: Decimal = format!("+{}{}.{}{}", "0".repeat(lead), int,
+                frac, "0".repeat(trail)
+            ).parse().unwrap();
+
+            prop_assert_eq!(plain, padded);
+        }
+
+        /// Rounded values are bounded by Down and Up, which differ by at most
+        /// one unit, and all modes agree if value is exact.
+        #[test]
+        fn prop_rounding_bounds(s in "[0-9]{1,9}\\.[0-9]{0,12}", scale in 0u8..=6) {
+            let decimal: Decimal = s.parse().unwrap();
+            let value = |r| decimal.to_price(scale, r).unwrap().value();
+
+            let down = value(Rounding::Down);
+            let up = value(Rounding::Up);
+
+            prop_assert!(up - down <= 1);
+            prop_assert!(down <= value(Rounding::HalfUp));
+            prop_assert!(value(Rounding::HalfUp) <= up);
+            prop_assert!(down <= value(Rounding::HalfEven));
+            prop_assert!(value(Rounding::HalfEven) <= up);
+
+            if decimal.scale() <= scale as u64 {
+                prop_assert_eq!(down, up);
+            }
+        }
+
+        /// Negative non-zero values are never converted into Price.
+        #[test]
+        fn prop_negative(s in "[0-9]{1,9}\\.[0-9]{0,9}") {
+            let decimal: Decimal = format!("-{}", s).parse().unwrap();
+
+            if decimal.is_zero() {
+                prop_assert!(decimal.to_price(9, Rounding::Down).is_ok());
+            }
+            else {
+                prop_assert!(decimal.is_negative());
+                prop_assert_eq!(decimal.to_price(9, Rounding::Down),
+                    Err(DecimalError::Negative)
+                );
+            }
+        }
+    }
+}
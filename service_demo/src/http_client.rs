@@ -0,0 +1,272 @@
+//! Configuration of HTTP client shared by HTTP sources.
+//!
+//! Single client is built at start and reused for all requests, so that
+//! connections are kept alive and pooled instead of being opened for every
+//! request. Every request is bounded by timeouts, so that hung connection
+//! can not stall collector.
+
+
+
+use std::{
+    fs,
+    str::FromStr,
+    time::Duration,
+};
+
+use reqwest::{
+    Certificate,
+    Client,
+    Proxy,
+    header::{
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+    },
+};
+
+use rate_limit::config::{
+    env_parse,
+    env_parse_millis,
+    env_string,
+};
+
+
+
+/// HTTP client configuration.
+///
+/// `connect_timeout` - time limit to establish connection.
+/// `read_timeout` - time limit between reads of response.
+/// `timeout` - time limit of whole request, including reading body.
+/// `pool_idle_timeout` - idle connections are closed after it.
+/// `pool_max_idle_per_host` - number of idle connections kept per host.
+/// `proxy` - URL of proxy that is used for all requests.
+/// `headers` - headers that are added to every request, i.e. API key.
+/// `tls_roots` - path to PEM file with additional trusted root certificates.
+/// `tls_builtin_roots` - if it is false, only `tls_roots` are trusted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+    pub proxy: Option<String>,
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub tls_roots: Option<String>,
+    pub tls_builtin_roots: bool,
+}
+
+
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 10,
+            proxy: None,
+            user_agent: concat!(env!("CARGO_PKG_NAME"), "/",
+                env!("CARGO_PKG_VERSION")
+            ).to_string(),
+            headers: Vec::new(),
+            tls_roots: None,
+            tls_builtin_roots: true,
+        }
+    }
+}
+
+
+
+/// Parse `Name: value` pairs separated by `|`.
+pub fn headers_parse(s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut ret = Vec::new();
+
+    for header in s.split('|').map(str::trim).filter(|h| !h.is_empty()) {
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(format!("expected `Name: value`, got: {}", header))
+        };
+
+        ret.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(ret)
+}
+
+
+
+impl HttpConfig {
+    /// Load configuration from ENV, parameters that are not set keep default
+    /// values.
+    ///
+    /// `HTTP_CONNECT_TIMEOUT_MILLIS`, `HTTP_READ_TIMEOUT_MILLIS`,
+    /// `HTTP_TIMEOUT_MILLIS`, `HTTP_POOL_IDLE_TIMEOUT_MILLIS` - timeouts.
+    /// `HTTP_POOL_MAX_IDLE_PER_HOST` - idle connections kept per host.
+    /// `HTTP_PROXY_URL` - proxy for all requests.
+    /// `HTTP_USER_AGENT` - User-Agent header.
+    /// `HTTP_HEADERS` - extra headers, see headers_parse.
+    /// `HTTP_TLS_ROOTS` - PEM file with additional root certificates.
+    /// `HTTP_TLS_BUILTIN_ROOTS` - whether built-in root certificates are
+    /// trusted, true by default.
+    pub fn from_env() -> Result<Self, String> {
+        let mut cfg = Self::default();
+
+        env_parse_millis("HTTP_CONNECT_TIMEOUT_MILLIS", &mut cfg.connect_timeout)?;
+        env_parse_millis("HTTP_READ_TIMEOUT_MILLIS", &mut cfg.read_timeout)?;
+        env_parse_millis("HTTP_TIMEOUT_MILLIS", &mut cfg.timeout)?;
+        env_parse_millis("HTTP_POOL_IDLE_TIMEOUT_MILLIS",
+            &mut cfg.pool_idle_timeout
+        )?;
+
+        let timeouts = [
+            ("HTTP_CONNECT_TIMEOUT_MILLIS", cfg.connect_timeout),
+            ("HTTP_READ_TIMEOUT_MILLIS", cfg.read_timeout),
+            ("HTTP_TIMEOUT_MILLIS", cfg.timeout),
+            ("HTTP_POOL_IDLE_TIMEOUT_MILLIS", cfg.pool_idle_timeout),
+        ];
+        if let Some((name, _)) = timeouts.iter().find(|(_, t)| t.is_zero()) {
+            return Err(format!("{} must be positive", name))
+        }
+        env_parse("HTTP_POOL_MAX_IDLE_PER_HOST", &mut cfg.pool_max_idle_per_host)?;
+        env_parse("HTTP_TLS_BUILTIN_ROOTS", &mut cfg.tls_builtin_roots)?;
+
+        cfg.proxy = env_string("HTTP_PROXY_URL");
+        cfg.tls_roots = env_string("HTTP_TLS_ROOTS");
+
+        if let Some(user_agent) = env_string("HTTP_USER_AGENT") {
+            cfg.user_agent = user_agent;
+        }
+
+        if let Some(headers) = env_string("HTTP_HEADERS") {
+            cfg.headers = headers_parse(&headers)
+                .map_err(|e| format!("HTTP_HEADERS is not valid: {}", e))?;
+        }
+
+        Ok(cfg)
+    }
+
+
+
+    /// Build client, it should be built once and cloned, clones share
+    /// connection pool.
+    pub fn build(&self) -> Result<Client, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_str(name)
+                .map_err(|e| format!("invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("invalid value of header {}: {}", name, e))?;
+
+            headers.append(name, value);
+        }
+
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .timeout(self.timeout)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .user_agent(&self.user_agent)
+            .default_headers(headers)
+            .tls_built_in_root_certs(self.tls_builtin_roots);
+
+        if let Some(ref proxy) = self.proxy {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| format!("invalid proxy {}: {}", proxy, e))?;
+
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(ref path) = self.tls_roots {
+            let pem = fs::read(path)
+                .map_err(|e| format!("could not read {}: {}", path, e))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("invalid certificates in {}: {}", path, e))?;
+
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        builder.build().map_err(|e| format!("could not build HTTP client: {}", e))
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpListener,
+        time::Instant,
+    };
+
+    #[test]
+    fn test_headers_parse() {
+        assert_eq!(headers_parse("Authorization: Bearer a:b | X-Id:1|"),
+            Ok(vec![
+                ("Authorization".to_string(), "Bearer a:b".to_string()),
+                ("X-Id".to_string(), "1".to_string()),
+            ])
+        );
+        assert_eq!(headers_parse(""), Ok(vec![]));
+        assert!(headers_parse("Authorization").is_err());
+
+        let cfg = HttpConfig {
+            headers: vec![("Bad Name".to_string(), "1".to_string())],
+            ..HttpConfig::default()
+        };
+        assert!(cfg.build().is_err());
+
+        let cfg = HttpConfig {
+            proxy: Some("not a url".to_string()),
+            ..HttpConfig::default()
+        };
+        assert!(cfg.build().is_err());
+    }
+
+    /// Configured headers are sent, request to endpoint that does not respond
+    /// fails once read timeout passes.
+    #[tokio::test]
+    async fn test_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0u8; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+
+            // Hang without response until client gives up.
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            stream.shutdown().await.unwrap();
+
+            String::from_utf8_lossy(&buf[..len]).to_lowercase()
+        });
+
+        let client = HttpConfig {
+            read_timeout: Duration::from_millis(200),
+            user_agent: "demo/1.0".to_string(),
+            headers: vec![("X-Api-Key".to_string(), "secret".to_string())],
+            ..HttpConfig::default()
+        }.build().unwrap();
+
+        let start = Instant::now();
+        let e = client.get(&url).send().await.unwrap_err();
+        assert!(e.is_timeout());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let request = server.await.unwrap();
+        assert!(request.contains("user-agent: demo/1.0\r\n"));
+        assert!(request.contains("x-api-key: secret\r\n"));
+    }
+}
@@ -7,29 +7,309 @@ use std::{
 };
 
 use dotenv;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use rate_limit::config::env_parse;
 
-pub mod async_http_collector;
+pub mod source;
 pub mod shared_state;
-pub mod rate_limit;
+pub mod http_client;
+pub mod metrics;
+pub mod price;
+pub mod decimal;
 pub mod price_info;
+pub mod symbol;
+pub mod consensus;
+pub mod validator;
 pub mod ohlc_calc;
 pub mod ohlc;
 pub mod storage;
 pub mod atomic_swap;
 pub mod terminal_output;
 
-use async_http_collector::AsyncHTTPCollector;
+use source::{
+    SourceKind,
+    async_http_collector::{
+        AsyncHTTPCollector,
+        Schema,
+    },
+    cadence::CadencePolicy,
+    websocket_collector::{
+        StreamSchema,
+        WebSocketCollector,
+    },
+    coincap::CoinCap,
+    binance::Binance,
+    replay::{
+        Replay,
+        ReplayFormat,
+    },
+    simulated::{
+        Simulated,
+        SimulatedConfig,
+    },
+};
 use shared_state::SharedState;
+use http_client::HttpConfig;
+use consensus::{
+    Consensus,
+    ConsensusPolicy,
+};
 use price_info::PriceInfo;
-use ohlc_calc::OhlcCalc;
+use symbol::Registry;
+use decimal::Rounding;
+use ohlc_calc::{
+    OhlcCalc,
+    OhlcMap,
+};
+use validator::{
+    Rejected,
+    Validator,
+    ValidatorPolicy,
+};
 use ohlc::Ohlc;
-use storage::postgres::Postgres;
+use rate_limit::{
+    bucket::{
+        self,
+        Buckets,
+        BucketPolicy,
+    },
+    skew::ClockSkew,
+};
+use storage::{
+    postgres::Postgres,
+    quarantine::Quarantine,
+};
 use atomic_swap::AtomicSwap;
 use terminal_output::TerminalOutput;
 
 
 
+/// Spawn one HTTP collector per configured asset, rate limited by local
+/// policy configured by `RATE_LIMIT_*` parameters. Collectors share single
+/// HTTP client configured by `HTTP_*` parameters, polling period is adaptive
+/// if `POLL_ADAPTIVE` is set.
+fn http_collectors_spawn<S: Schema + Clone>(schema: S, url: &str,
+    registry: &Registry, rounding: Rounding, tx: &mpsc::Sender<PriceInfo>,
+    state: &Arc<SharedState>, hs: &mut JoinSet<()>
+)
+    -> Result<(), String>
+{
+    // Local rate limiting policy, collectors that request the same host share
+    // single bucket.
+    let buckets = Buckets::new(BucketPolicy::from_env()?);
+
+    let client = HttpConfig::from_env()?.build()?;
+    let cadence = CadencePolicy::from_env()?;
+
+    // All collectors request the same server, so they share clock offset
+    // estimate as well.
+    let skew = Arc::new(ClockSkew::default());
+    state.metrics.register(
+        &format!("clock_skew_millis{{host=\"{}\"}}", bucket::host_key(url)),
+        skew.metric()
+    );
+
+    for asset in registry.assets() {
+        let mut collector = AsyncHTTPCollector::new(schema.clone(), url, asset,
+            tx.clone()
+        );
+        collector.request_period_millis_set(800);
+        collector.rounding_set(rounding);
+        collector.bucket_set(buckets.get(url));
+        collector.skew_set(skew.clone());
+        collector.client_set(client.clone());
+        if let Some(cadence) = cadence {
+            collector.cadence_set(cadence);
+        }
+
+        hs.spawn(source::main(collector, state.clone()));
+    }
+
+    Ok(())
+}
+
+
+
+/// Spawn single WebSocket collector for all configured assets.
+fn ws_collector_spawn<S: StreamSchema>(schema: S, url: &str,
+    registry: &Registry, rounding: Rounding, tx: &mpsc::Sender<PriceInfo>,
+    state: &Arc<SharedState>, hs: &mut JoinSet<()>
+)
+{
+    let mut collector = WebSocketCollector::new(schema, url, registry.assets(),
+        tx.clone()
+    );
+    collector.rounding_set(rounding);
+
+    hs.spawn(source::main(collector, state.clone()));
+}
+
+
+
+/// Spawn replay of historical rates, configured by `REPLAY_FORMAT` and
+/// `REPLAY_SPEED`, HTTP locations are loaded with client configured by
+/// `HTTP_*` parameters.
+fn replay_spawn(location: &str, registry: &Registry, rounding: Rounding,
+    tx: &mpsc::Sender<PriceInfo>, state: &Arc<SharedState>,
+    hs: &mut JoinSet<()>
+)
+    -> Result<(), String>
+{
+    let format = env::var("REPLAY_FORMAT").unwrap_or_else(|_| "csv".to_string());
+    let format = format.parse::<ReplayFormat>()?;
+
+    let speed = env::var("REPLAY_SPEED").unwrap_or_else(|_| "0".to_string());
+    let speed = speed.trim().parse::<u32>()
+        .map_err(|_| format!("invalid replay speed: {}", speed))?;
+
+    let mut replay = Replay::new(location, format, registry.assets(), tx.clone());
+    replay.speed_set(speed);
+    replay.rounding_set(rounding);
+    replay.client_set(HttpConfig::from_env()?.build()?);
+
+    hs.spawn(source::main(replay, state.clone()));
+
+    Ok(())
+}
+
+
+
+/// Spawn simulated source for all configured assets, configured by `SIM_*`
+/// parameters.
+fn simulated_spawn(registry: &Registry, tx: &mpsc::Sender<PriceInfo>,
+    state: &Arc<SharedState>, hs: &mut JoinSet<()>
+)
+    -> Result<(), String>
+{
+    let cfg = SimulatedConfig::from_env()?;
+    let simulated = Simulated::new(cfg, registry.assets(), tx.clone())?;
+
+    hs.spawn(source::main(simulated, state.clone()));
+
+    Ok(())
+}
+
+
+
+/// Load endpoint URL and assets of source, parameter names are prefixed by
+/// `prefix`, i.e. `BINANCE_URL_RATES`. Simulated source needs no URL.
+fn source_config(kind: SourceKind, prefix: &str)
+    -> Result<(String, Registry), String>
+{
+    let url = match env::var(format!("{}URL_RATES", prefix)) {
+        Ok(url) => url,
+        Err(..) if kind == SourceKind::Simulated => String::new(),
+        Err(..) => return Err(format!("{}URL_RATES must be configured in .env file.",
+            prefix
+        )),
+    };
+
+    // One collector is spawned per configured asset.
+    let assets = env::var(format!("{}ASSETS", prefix))
+        .unwrap_or_else(|_| "bitcoin".to_string());
+    let registry = Registry::parse(&assets)
+        .map_err(|e| format!("{}ASSETS configuration is not valid: {}", prefix, e))?;
+
+    if registry.assets().is_empty() {
+        return Err(format!("{}ASSETS must contain at least one asset.", prefix))
+    }
+
+    Ok((url, registry))
+}
+
+
+
+/// Spawn source of given kind that sends rates into `tx`.
+fn source_spawn(kind: SourceKind, url: &str, registry: &Registry,
+    rounding: Rounding, tx: &mpsc::Sender<PriceInfo>,
+    state: &Arc<SharedState>, hs: &mut JoinSet<()>
+)
+    -> Result<(), String>
+{
+    match kind {
+        SourceKind::CoinCap => http_collectors_spawn(CoinCap, url, registry,
+            rounding, tx, state, hs
+        ).map_err(|e| format!("HTTP configuration is not valid: {}", e)),
+        SourceKind::Binance => http_collectors_spawn(Binance, url, registry,
+            rounding, tx, state, hs
+        ).map_err(|e| format!("HTTP configuration is not valid: {}", e)),
+        SourceKind::CoinCapWs => {
+            ws_collector_spawn(CoinCap, url, registry, rounding, tx, state, hs);
+            Ok(())
+        }
+        SourceKind::BinanceWs => {
+            ws_collector_spawn(Binance, url, registry, rounding, tx, state, hs);
+            Ok(())
+        }
+        SourceKind::Replay => replay_spawn(url, registry, rounding, tx, state,
+            hs
+        ).map_err(|e| format!("replay configuration is not valid: {}", e)),
+        SourceKind::Simulated => simulated_spawn(registry, tx, state, hs)
+            .map_err(|e| format!("simulation configuration is not valid: {}", e)),
+        SourceKind::Consensus => Err("consensus can not be nested".to_string()),
+    }
+}
+
+
+
+/// Spawn sources listed in `CONSENSUS_SOURCES` as `name[:weight]` separated
+/// by commas and consensus that combines their rates, configured by
+/// `CONSENSUS_*` parameters. Every source is configured by its own prefixed
+/// parameters, i.e. `BINANCE_URL_RATES`, `BINANCE_ASSETS`.
+fn consensus_spawn(rounding: Rounding, tx: &mpsc::Sender<PriceInfo>,
+    state: &Arc<SharedState>, hs: &mut JoinSet<()>
+)
+    -> Result<(), String>
+{
+    let policy = ConsensusPolicy::from_env()
+        .map_err(|e| format!("consensus configuration is not valid: {}", e))?;
+    let mut consensus = Consensus::new(policy, tx.clone());
+
+    let sources = env::var("CONSENSUS_SOURCES").unwrap_or_default();
+    let mut kinds = Vec::new();
+    for source in sources.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (name, weight) = match source.split_once(':') {
+            Some((name, weight)) => match weight.trim().parse::<u64>() {
+                Ok(weight) if weight > 0 => (name, weight),
+                _ => return Err(format!("CONSENSUS_SOURCES weight is not valid: {}",
+                    source
+                )),
+            },
+            None => (source, 1),
+        };
+
+        let kind = name.parse::<SourceKind>()
+            .map_err(|e| format!("CONSENSUS_SOURCES is not valid: {}", e))?;
+        if matches!(kind, SourceKind::Replay | SourceKind::Consensus) {
+            return Err(format!("{} can not be used in consensus", kind))
+        }
+        if kinds.contains(&kind) {
+            return Err(format!("{} is listed in CONSENSUS_SOURCES twice", kind))
+        }
+        kinds.push(kind);
+
+        let prefix = format!("{}_", kind.to_string().to_ascii_uppercase());
+        let (url, registry) = source_config(kind, &prefix)?;
+
+        let input = consensus.input_add(&kind.to_string(), weight);
+        source_spawn(kind, &url, &registry, rounding, &input, state, hs)?;
+    }
+
+    if kinds.is_empty() {
+        return Err("CONSENSUS_SOURCES must contain at least one source.".to_string())
+    }
+
+    hs.spawn(consensus::main(consensus, state.clone()));
+
+    Ok(())
+}
+
+
+
 #[tokio::main]
 async fn main() {
     match dotenv::from_path(".env") {
@@ -40,26 +320,131 @@ async fn main() {
         }
     }
 
-    let Ok(url_rates) = env::var("URL_RATES") else {
-        eprintln!("ERROR: URL_RATES must be configured in .env file.");
+    let source_kind = env::var("SOURCE").unwrap_or_else(|_| "coincap".to_string());
+    let source_kind = match source_kind.parse::<SourceKind>() {
+        Ok(source_kind) => source_kind,
+        Err(e) => {
+            eprintln!("ERROR: SOURCE configuration is not valid: {}", e);
+            return
+        }
+    };
+
+    // Ohlc durations that are calculated from the same rates.
+    let timeframes = env::var("TIMEFRAMES").unwrap_or_else(|_| "1m".to_string());
+    let timeframes = match ohlc_calc::timeframes_parse(&timeframes) {
+        Ok(timeframes) if !timeframes.is_empty() => timeframes,
+        Ok(..) => {
+            eprintln!("ERROR: TIMEFRAMES must contain at least one timeframe.");
+            return
+        }
+        Err(e) => {
+            eprintln!("ERROR: TIMEFRAMES configuration is not valid: {}", e);
+            return
+        }
+    };
+
+    // Rounding for rates that have more decimal places than can be stored.
+    let rounding = match env::var("RATE_ROUNDING") {
+        Ok(rounding) => match rounding.parse::<Rounding>() {
+            Ok(rounding) => rounding,
+            Err(e) => {
+                eprintln!("ERROR: RATE_ROUNDING configuration is not valid: {}", e);
+                return
+            }
+        },
+        Err(..) => Rounding::default(),
+    };
+
+    // How long after end of Ohlc period late ticks still amend it, delay
+    // after which wall clock finishes Ohlc of stalled source and whether
+    // periods without ticks get synthetic Ohlc. Longer gaps are not filled,
+    // so that stall does not flood storage.
+    let mut lateness = 0u64;
+    let mut grace = 10u64;
+    let mut gap_fill = false;
+    let mut gap_fill_max = 1440u32;
+
+    let r = env_parse("OHLC_ALLOWED_LATENESS_SECS", &mut lateness)
+        .and_then(|()| env_parse("OHLC_FINALIZE_GRACE_SECS", &mut grace))
+        .and_then(|()| env_parse("OHLC_GAP_FILL", &mut gap_fill))
+        .and_then(|()| env_parse("OHLC_GAP_FILL_MAX", &mut gap_fill_max));
+
+    if let Err(e) = r {
+        eprintln!("ERROR: Ohlc configuration is not valid: {}", e);
         return
+    }
+
+    // Spike filter thresholds, ticks are validated before they reach calc.
+    let mut validator_policy = match ValidatorPolicy::from_env() {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("ERROR: validation configuration is not valid: {}", e);
+            return
+        }
     };
 
+    validator_policy.lateness = lateness;
+
+    // Replayed and simulated ticks are not tied to wall clock.
+    if matches!(source_kind, SourceKind::Replay | SourceKind::Simulated) {
+        validator_policy.max_future = 0;
+    }
+
     let state = Arc::new(SharedState::default());
 
-    let boxed_ohlc = Box::new(Some(Ohlc::default()));
+    let boxed_ohlc = Box::new(Some(OhlcMap::new()));
     let terminal_ohlc = Arc::new(AtomicSwap::new(boxed_ohlc));
 
     let (tx, rx) = mpsc::channel::<PriceInfo>(200);
+    let (tx_valid, rx_valid) = mpsc::channel::<PriceInfo>(200);
+    let (tx_quarantine, rx_quarantine) = mpsc::channel::<Rejected>(200);
     let (tx_storage, rx_storage) = mpsc::channel::<Ohlc>(200);
 
-    // TODO: for now we just build a single collector, but technically we could
-    // fork multiple collectors for multiple crypto rates based on .env config.
-    let url = format!("{}/bitcoin", url_rates);
-    let mut collector = AsyncHTTPCollector::new(&url, tx);
-    collector.request_period_millis_set(800);
+    // All sources send PriceInfo into the same channel.
+    let mut collector_hs = JoinSet::new();
+    let r = match source_kind {
+        SourceKind::Consensus => consensus_spawn(rounding, &tx, &state,
+            &mut collector_hs
+        ),
+        kind => source_config(kind, "").and_then(|(url, registry)| {
+            source_spawn(kind, &url, &registry, rounding, &tx, &state,
+                &mut collector_hs
+            )
+        }),
+    };
 
-    let calc = OhlcCalc::new(rx, tx_storage, terminal_ohlc.clone());
+    if let Err(e) = r {
+        eprintln!("ERROR: {}", e);
+        return
+    }
+
+    // Validator must see channel closed once all collectors are gone.
+    drop(tx);
+
+    let validator = Validator::new(validator_policy, rx, tx_valid,
+        tx_quarantine
+    );
+
+    let mut quarantine = Quarantine::new(rx_quarantine);
+    if let Ok(path) = env::var("QUARANTINE_PATH") {
+        quarantine.path_set(&path);
+    }
+
+    let mut calc = OhlcCalc::new(rx_valid, tx_storage, terminal_ohlc.clone());
+    calc.timeframes_set(timeframes);
+    calc.lateness_set(lateness);
+    calc.gap_fill_set(gap_fill);
+    calc.gap_fill_max_set(gap_fill_max);
+    calc.rounding_set(rounding);
+
+    // Replayed and simulated ticks are not tied to wall clock, they are
+    // finite history that must be stored completely.
+    if matches!(source_kind, SourceKind::Replay | SourceKind::Simulated) {
+        calc.history_set(true);
+    }
+    else {
+        calc.grace_set(grace);
+    }
 
     // TODO: here based on configuration we could choose different storage
     // implementation, like MongoDB, Redis, CSV file, etc.
@@ -68,9 +453,8 @@ async fn main() {
 
     let terminal = TerminalOutput::new(terminal_ohlc);
 
-    let collector_h = tokio::spawn(
-        async_http_collector::main(collector, state.clone())
-    );
+    let validator_h = tokio::spawn(validator::main(validator, state.clone()));
+    let quarantine_h = tokio::spawn(storage::main(quarantine, state.clone()));
     let calc_h = tokio::spawn(ohlc_calc::main(calc, state.clone()));
     let storage_h = tokio::spawn(storage::main(storage, state.clone()));
     let terminal_h = tokio::spawn(terminal_output::main(terminal, state.clone()));
@@ -91,11 +475,35 @@ async fn main() {
     // cases we should implement more complex code here that is able to recover
     // process from partially crashed state.
 
-    let _ = collector_h.await;
-    state.shut_down.store(1, Ordering::Relaxed);
+    if matches!(source_kind, SourceKind::Replay | SourceKind::Simulated) {
+        // Replay and limited simulation stop once all data is sent. Validator,
+        // calc and storage stop after they have processed everything left in
+        // channels, only then other tasks are asked to shut down.
+        while collector_hs.join_next().await.is_some() {}
+
+        let _ = validator_h.await;
+        let _ = calc_h.await;
+        let _ = storage_h.await;
+        let _ = quarantine_h.await;
+        state.shut_down.store(1, Ordering::Relaxed);
+    }
+    else {
+        // If any collector stops, service is considered broken and all other
+        // tasks are asked to shut down.
+        let _ = collector_hs.join_next().await;
+        state.shut_down.store(1, Ordering::Relaxed);
+
+        while collector_hs.join_next().await.is_some() {}
+
+        let _ = validator_h.await;
+        let _ = calc_h.await;
+        let _ = storage_h.await;
+        let _ = quarantine_h.await;
+    }
+
+    // Signal is not going to come if shut down was not caused by it.
+    sig_h.abort();
 
-    let _ = calc_h.await;
-    let _ = storage_h.await;
     let _ = terminal_h.await;
     let _ = sig_h.await;
 }
@@ -0,0 +1,103 @@
+//! Runtime metrics.
+//!
+//! Metric is an atomic value registered by name, i.e.
+//! `clock_skew_millis{host="api.coincap.io:443"}`. Tasks update their metrics
+//! without locking, terminal output prints all of them periodically.
+
+
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+        atomic::{
+            AtomicI64,
+            Ordering,
+        },
+    },
+};
+
+
+
+/// Registry of named metrics.
+///
+/// BTreeMap is used so that metrics are always listed in the same order.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<String, Arc<AtomicI64>>>,
+}
+
+
+
+impl Metrics {
+    /// Register metric that is updated by its owner. Metric with the same
+    /// name is replaced.
+    pub fn register(&self, name: &str, value: Arc<AtomicI64>) {
+        self.lock().insert(name.to_string(), value);
+    }
+
+
+
+    /// Get metric by name, it is created with value 0 if it does not exist.
+    pub fn get(&self, name: &str) -> Arc<AtomicI64> {
+        self.lock().entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+
+
+    /// Current values of all metrics.
+    pub fn snapshot(&self) -> Vec<(String, i64)> {
+        self.lock().iter()
+            .map(|(name, value)| (name.clone(), value.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Arc<AtomicI64>>> {
+        // Values stay valid even if some thread has panicked while holding
+        // the lock.
+        match self.values.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+
+
+/// Metrics in text exposition format, one `name value` line per metric.
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.snapshot() {
+            writeln!(f, "{} {}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::default();
+
+        let skew = Arc::new(AtomicI64::new(-250));
+        metrics.register("clock_skew_millis", skew.clone());
+        metrics.get("b_total").fetch_add(2, Ordering::Relaxed);
+        metrics.get("b_total").fetch_add(1, Ordering::Relaxed);
+        skew.store(120, Ordering::Relaxed);
+
+        assert_eq!(metrics.to_string(), "b_total 3\nclock_skew_millis 120\n");
+    }
+}
@@ -1,22 +1,120 @@
 
 
 
+use std::fmt;
+
+use crate::{
+    price::Price,
+    symbol::Symbol,
+};
+
+
+
 /// Stores Open-High-Low-Close price information per specified duration.
 ///
-/// Ohlc always uses 4 decimal places. It is hardcoded.
+/// Ohlc always uses 4 decimal places, see Ohlc::SCALE. It is hardcoded, so that
+/// all stored rows are comparable.
 ///
+/// `base`, `quote` - pair that this Ohlc is calculated for, i.e. BTC/USD.
 /// `duration` - used to define Ohlc time duration in seconds so that OHLC can
 /// represent various calculation durations like, 1 min, 5 min, 1 hour, etc.
 /// I.e. 1 minute duration = 60, start will be a round Unix timestamp for
 /// specified minute.
-#[derive(Default, Debug, Clone)]
+/// `revision` - 0 when Ohlc is emitted for the first time, it is incremented
+/// every time late tick amends Ohlc that was already emitted.
+/// `synthetic` - Ohlc was made up for period without ticks, all prices are
+/// close of previous Ohlc.
+/// `ticks` - number of ticks that Ohlc is made of, 0 for synthetic Ohlc.
+/// `ts_first`, `ts_last` - timestamps of the first and the last tick.
+/// `mean` - mean of tick rates.
+/// `twap` - time weighted average price, each rate lasts until the next tick
+/// and the last one until end of period.
+/// `volume` - traded amount of base with Ohlc::VOLUME_SCALE, it is None if
+/// source does not supply volume.
+/// `vwap` - volume weighted average price of ticks that have volume.
+#[derive(Debug, Clone)]
 pub struct Ohlc {
+    pub base: Symbol,
+    pub quote: Symbol,
     pub start: u64,
-    pub open: u64,
-    pub high: u64,
-    pub low: u64,
-    pub close: u64,
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
     pub duration: u32,
+    pub revision: u32,
+    pub synthetic: bool,
+    pub ticks: u64,
+    pub ts_first: u64,
+    pub ts_last: u64,
+    pub mean: Price,
+    pub twap: Price,
+    pub volume: Option<Price>,
+    pub vwap: Option<Price>,
+}
+
+
+
+impl Ohlc {
+    /// Number of decimal places used for all Ohlc prices.
+    pub const SCALE: u8 = 4;
+
+    /// Number of decimal places used for volume, traded amounts can be much
+    /// smaller than prices.
+    pub const VOLUME_SCALE: u8 = 8;
+
+
+
+    /// Start new Ohlc from the first known rate, that is timestamped at
+    /// start.
+    ///
+    /// Rate must already be rescaled to Ohlc::SCALE.
+    pub fn new(base: Symbol, quote: Symbol, start: u64, duration: u32,
+        rate: Price
+    )
+        -> Self
+    {
+        Self {
+            base, quote, start,
+            open: rate,
+            high: rate,
+            low: rate,
+            close: rate,
+            duration,
+            revision: 0,
+            synthetic: false,
+            ticks: 1,
+            ts_first: start,
+            ts_last: start,
+            mean: rate,
+            twap: rate,
+            volume: None,
+            vwap: None,
+        }
+    }
 }
 
 
+
+impl fmt::Display for Ohlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} start: {} duration: {} open: {} high: {} low: {} close: {}",
+            self.base, self.quote, self.start, self.duration, self.open,
+            self.high, self.low, self.close
+        )?;
+
+        write!(f, " ticks: {} first: {} last: {} mean: {} twap: {}",
+            self.ticks, self.ts_first, self.ts_last, self.mean, self.twap
+        )?;
+
+        if let (Some(volume), Some(vwap)) = (self.volume, self.vwap) {
+            write!(f, " volume: {} vwap: {}", volume, vwap)?;
+        }
+
+        if self.synthetic {
+            write!(f, " synthetic")?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,103 +1,1070 @@
-use std::sync::{
-    Arc,
-    atomic::Ordering,
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::{
+        Duration,
+        UNIX_EPOCH,
+    },
 };
 
-use tokio::sync::mpsc;
+use tokio::{
+    sync::mpsc,
+    time::Instant,
+};
+
+use rate_limit::clock::{
+    Clock,
+    SystemClock,
+};
 
 use crate::{
     shared_state::SharedState,
-    price_info::{
-        PriceInfo,
-    },
+    decimal::Rounding,
+    price::Price,
+    price_info::PriceInfo,
+    symbol::Symbol,
     atomic_swap::AtomicSwap,
     ohlc::Ohlc,
 };
 
 
 
+/// Minimal monotonic time between snapshots published to terminal. Terminal
+/// is updated once per second, there is no need to copy all Ohlc for every
+/// tick when they arrive fast, i.e. from stream or replay.
+const SNAPSHOT_PERIOD: Duration = Duration::from_millis(100);
+
+/// Period of timer that finishes Ohlc by wall clock.
+const FINALIZE_PERIOD: Duration = Duration::from_secs(1);
+
+
+
+/// Current Ohlc for every base/quote pair and duration that has received
+/// data.
+///
+/// BTreeMap is used so that pairs are always listed in the same order.
+pub type OhlcMap = BTreeMap<(Symbol, Symbol, u32), Ohlc>;
+
+
+
+/// Calculates Ohlc for each configured timeframe.
+///
+/// Ticks are bucketed into Ohlc by their own timestamps, that is exchange
+/// time if endpoint provides it, clock is used only to schedule terminal
+/// snapshots.
+///
+/// Watermark of a pair is the newest tick timestamp seen for it. Ohlc is
+/// finished and sent to storage once watermark reaches end of its period.
+/// Late ticks are routed into Ohlc of their own period, if that Ohlc was
+/// already sent, amended Ohlc is sent again with incremented revision, so
+/// storage can update stored row. Ticks whose period has ended more than
+/// `lateness` before watermark are dropped.
+///
+/// If source stalls, no tick moves watermark, so if `grace` is set, timer
+/// moves it to wall clock minus `grace` as well. If `gap_fill` is set,
+/// periods without ticks get flat synthetic Ohlc once watermark passes them,
+/// but no more than `gap_fill_max` in a row, so that long stall or gap in
+/// history does not flood storage.
+/// If late tick changes close of Ohlc, synthetic Ohlc that follow it are
+/// amended with new close as well.
+///
+/// Ticks of history, i.e. replay or simulation, are sent as fast as calc
+/// accepts them, so for them `history` is set: Ohlc are never dropped if
+/// storage can not keep up, calc waits for it instead, and Ohlc that are
+/// still open when input ends are finished and stored, since no more ticks
+/// will come.
+///
+/// `timeframes` - Ohlc durations in seconds, by default only 1 minute Ohlc
+/// is calculated.
+/// `lateness` - allowed lateness in seconds, 0 by default.
+/// `grace` - delay of wall clock watermark in seconds, timer is not used if
+/// it is not set, i.e. for replayed ticks that are not tied to wall clock.
+/// `gap_fill` - whether synthetic Ohlc is made for periods without ticks.
+/// `gap_fill_max` - maximum number of synthetic Ohlc in a row, 1440 by
+/// default.
+/// `history` - whether ticks are finite history, false by default.
+/// `rounding` - rounding of rates that have more decimal places than
+/// Ohlc::SCALE.
 pub struct OhlcCalc {
     rx: mpsc::Receiver<PriceInfo>,
     tx_storage: mpsc::Sender<Ohlc>,
-    terminal: Arc<AtomicSwap<Option<Ohlc>>>,
+    terminal: Arc<AtomicSwap<Option<OhlcMap>>>,
+    timeframes: Vec<u32>,
+    lateness: u64,
+    grace: Option<u64>,
+    gap_fill: bool,
+    gap_fill_max: u32,
+    history: bool,
+    rounding: Rounding,
+    clock: Arc<dyn Clock>,
 }
 
 
 
 impl OhlcCalc {
     pub fn new(rx: mpsc::Receiver<PriceInfo>, tx_storage: mpsc::Sender<Ohlc>,
-        terminal: Arc<AtomicSwap<Option<Ohlc>>>
+        terminal: Arc<AtomicSwap<Option<OhlcMap>>>
     )
         -> Self
     {
         Self {
             rx, tx_storage, terminal,
+            timeframes: vec![60],
+            lateness: 0,
+            grace: None,
+            gap_fill: false,
+            gap_fill_max: 1440,
+            history: false,
+            rounding: Rounding::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+
+
+    /// Set Ohlc durations in seconds that must be calculated.
+    ///
+    /// Durations are sorted and deduplicated, zero durations are ignored. If
+    /// no valid durations are given, configuration is not changed.
+    pub fn timeframes_set(&mut self, mut timeframes: Vec<u32>) {
+        timeframes.retain(|tf| *tf > 0);
+        timeframes.sort_unstable();
+        timeframes.dedup();
+
+        if timeframes.is_empty() {
+            return
         }
+
+        self.timeframes = timeframes;
+    }
+
+
+
+    /// Set allowed lateness in seconds, late ticks amend finished Ohlc until
+    /// watermark is that far past end of its period.
+    pub fn lateness_set(&mut self, lateness: u64) {
+        self.lateness = lateness;
+    }
+
+
+
+    /// Finish Ohlc by wall clock as well, once it is `grace` seconds past
+    /// end of Ohlc period.
+    pub fn grace_set(&mut self, grace: u64) {
+        self.grace = Some(grace);
+    }
+
+
+
+    /// Make flat synthetic Ohlc for periods without ticks.
+    pub fn gap_fill_set(&mut self, gap_fill: bool) {
+        self.gap_fill = gap_fill;
+    }
+
+
+
+    /// Set maximum number of synthetic Ohlc in a row, longer gaps are left
+    /// unfilled.
+    pub fn gap_fill_max_set(&mut self, gap_fill_max: u32) {
+        self.gap_fill_max = gap_fill_max;
+    }
+
+
+
+    /// Treat ticks as finite history, Ohlc wait for storage instead of being
+    /// dropped and open Ohlc are finished once input ends.
+    pub fn history_set(&mut self, history: bool) {
+        self.history = history;
+    }
+
+
+
+    /// Set rounding mode for rates that have more decimal places than Ohlc
+    /// can store.
+    pub fn rounding_set(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
     }
 }
 
 
 
-pub async fn main(mut calc: OhlcCalc, shared_state: Arc<SharedState>) {
-    let mut ohlc = Ohlc::default();
+/// Parse comma separated list of timeframes, i.e. "1m,5m,15m,1h,1d".
+///
+/// Each timeframe is a number followed by unit `s`, `m`, `h` or `d`, plain
+/// number is treated as seconds.
+pub fn timeframes_parse(timeframes: &str) -> Result<Vec<u32>, String> {
+    let mut ret = Vec::new();
+
+    for tf in timeframes.split(',').map(str::trim).filter(|tf| !tf.is_empty()) {
+        let (num, mul) = match tf.char_indices().last() {
+            Some((pos, 's')) => (&tf[..pos], 1),
+            Some((pos, 'm')) => (&tf[..pos], 60),
+            Some((pos, 'h')) => (&tf[..pos], 3600),
+            Some((pos, 'd')) => (&tf[..pos], 86400),
+            _ => (tf, 1),
+        };
+
+        let duration = num.parse::<u32>().ok()
+            .and_then(|num| num.checked_mul(mul))
+            .filter(|duration| *duration > 0);
+
+        let Some(duration) = duration else {
+            return Err(format!("invalid timeframe: {}", tf))
+        };
+
+        ret.push(duration);
+    }
+
+    Ok(ret)
+}
+
+
+
+// Rounded quotient as price with given scale, None if it does not fit.
+fn price_div(num: u128, div: u128, scale: u8) -> Option<Price> {
+    if div == 0 {
+        return None
+    }
+
+    let value = u64::try_from((num + div / 2) / div).ok()?;
+
+    Price::new(value, scale).ok()
+}
+
+
+
+/// Ohlc together with sums that its averages are calculated from.
+///
+/// `sum` - sum of tick rates.
+/// `area` - sum of rates multiplied by seconds they lasted, up to the last
+/// tick.
+/// `volume` - sum of tick volumes with Ohlc::VOLUME_SCALE, None if no tick
+/// had volume.
+/// `turnover` - sum of tick rates multiplied by their volumes.
+#[derive(Debug, Clone)]
+struct Candle {
+    ohlc: Ohlc,
+    sum: u128,
+    area: u128,
+    volume: Option<u128>,
+    turnover: u128,
+}
+
+
+
+impl Candle {
+    fn new(info: &PriceInfo, rate: Price, start: u64, duration: u32) -> Self {
+        let mut ohlc = Ohlc::new(info.base, info.quote, start, duration, rate);
+        ohlc.ts_first = info.timestamp;
+        ohlc.ts_last = info.timestamp;
+
+        let mut candle = Self {
+            ohlc,
+            sum: rate.value() as u128,
+            area: 0,
+            volume: None,
+            turnover: 0,
+        };
+        candle.volume_add(info, rate);
+        candle.stats();
+
+        candle
+    }
 
-    let mut terminal_ohlc: Box<Option<Ohlc>> = Box::new(None);
 
-    // If collector thread has crashed, this thread has no use to be alive.
-    while let Some(info) = calc.rx.recv().await {
-        let Some(rate) = info.rate else { continue };
 
-        if info.decimal != 4 {
-            todo!("must normalize incomming data before using it in calculations")
+    /// Flat Ohlc for period without ticks, that continues from `prev`.
+    fn synthetic(prev: &Ohlc, start: u64) -> Self {
+        let mut ohlc = Ohlc::new(prev.base, prev.quote, start, prev.duration,
+            prev.close
+        );
+        ohlc.synthetic = true;
+        ohlc.ticks = 0;
+
+        Self {
+            ohlc,
+            sum: 0,
+            area: 0,
+            volume: None,
+            turnover: 0,
         }
+    }
 
-        let ts_unix_minute = info.timestamp - (info.timestamp % 60);
 
-        // If new minute has started, reset values and store current into DB.
-        if ohlc.start != ts_unix_minute {
-            let ohlc_prev = ohlc;
 
-            ohlc = Ohlc::default();
-            ohlc.start = ts_unix_minute;
-            ohlc.duration = 60;
-            ohlc.open = rate;
-            ohlc.low = rate;
+    fn update(&mut self, info: &PriceInfo, rate: Price) {
+        let ohlc = &mut self.ohlc;
+        let value = rate.value() as u128;
+
+        if ohlc.high < rate {
             ohlc.high = rate;
+        }
+
+        if ohlc.low > rate {
+            ohlc.low = rate;
+        }
+
+        // Late tick that falls between known ticks does not change TWAP, it
+        // is not known how long its rate lasted.
+        if info.timestamp < ohlc.ts_first {
+            self.area += value * (ohlc.ts_first - info.timestamp) as u128;
+            ohlc.open = rate;
+            ohlc.ts_first = info.timestamp;
+        }
+        // Any value that is not older is considered a close, because we do
+        // not know if we will get data for the same second in next message.
+        else if info.timestamp >= ohlc.ts_last {
+            self.area += ohlc.close.value() as u128
+                * (info.timestamp - ohlc.ts_last) as u128;
             ohlc.close = rate;
+            ohlc.ts_last = info.timestamp;
+        }
 
-            if ohlc_prev.start != 0 {
-                // Loose data if DB backend can not keep up.
-                if let Err(..) = calc.tx_storage.try_send(ohlc_prev) {
-                    eprintln!(concat!("Storage backend can not keep up with",
-                        " generated data. dropping Ohlc."
-                    ));
-                }
+        ohlc.ticks += 1;
+        self.sum += value;
+        self.volume_add(info, rate);
+        self.stats();
+    }
+
+
+
+    fn volume_add(&mut self, info: &PriceInfo, rate: Price) {
+        let volume = info.volume
+            .and_then(|volume| volume.rescale(Ohlc::VOLUME_SCALE).ok());
+        let Some(volume) = volume else { return };
+
+        let volume = volume.value() as u128;
+        self.volume = Some(self.volume.unwrap_or(0) + volume);
+        self.turnover += rate.value() as u128 * volume;
+    }
+
+
+
+    /// Update averages of Ohlc from sums.
+    fn stats(&mut self) {
+        let ohlc = &mut self.ohlc;
+        let end = ohlc.start + ohlc.duration as u64;
+        let close = ohlc.close.value() as u128;
+
+        ohlc.mean = price_div(self.sum, ohlc.ticks as u128, Ohlc::SCALE)
+            .unwrap_or(ohlc.close);
+
+        // The last rate lasts until end of period.
+        let area = self.area + close * (end - ohlc.ts_last) as u128;
+        ohlc.twap = price_div(area, (end - ohlc.ts_first) as u128, Ohlc::SCALE)
+            .unwrap_or(ohlc.close);
+
+        if let Some(volume) = self.volume {
+            ohlc.volume = price_div(volume, 1, Ohlc::VOLUME_SCALE);
+            ohlc.vwap = price_div(self.turnover, volume, Ohlc::SCALE);
+        }
+    }
+}
+
+
+
+/// Ohlc of single pair and duration that can still change.
+///
+/// `open` - Ohlc whose period has not been reached by watermark, by start.
+/// `finished` - Ohlc that were sent to storage, but can be amended by late
+/// ticks, by start.
+/// `last` - the newest finished Ohlc, gaps after it are filled with its
+/// close.
+/// `filled` - number of synthetic Ohlc finished in a row.
+#[derive(Debug, Clone, Default)]
+struct Series {
+    open: BTreeMap<u64, Candle>,
+    finished: BTreeMap<u64, Candle>,
+    last: Option<Ohlc>,
+    filled: u32,
+}
+
+
+
+impl Series {
+    /// Route rate, that is rescaled to Ohlc::SCALE, into Ohlc of its period.
+    ///
+    /// Ohlc that must be sent to storage are pushed to `done`. Returns false
+    /// if tick is too late and is dropped.
+    fn update(&mut self, info: &PriceInfo, rate: Price, duration: u32,
+        watermark: u64, lateness: u64, done: &mut Vec<Ohlc>
+    )
+        -> bool
+    {
+        let duration_u64 = duration as u64;
+        let start = info.timestamp - (info.timestamp % duration_u64);
+        let end = start + duration_u64;
+
+        if let Some(candle) = self.open.get_mut(&start) {
+            candle.update(info, rate);
+            return true
+        }
+
+        if let Some(candle) = self.finished.get_mut(&start) {
+            // Synthetic prices were not real, the first tick replaces them.
+            if candle.ohlc.synthetic {
+                let revision = candle.ohlc.revision;
+                *candle = Candle::new(info, rate, start, duration);
+                candle.ohlc.revision = revision;
+            }
+            else {
+                candle.update(info, rate);
+            }
+            candle.ohlc.revision += 1;
+            done.push(candle.ohlc.clone());
+
+            if self.last.as_ref().is_some_and(|last| last.start == start) {
+                self.last = Some(candle.ohlc.clone());
+            }
+
+            self.refill(start, done);
+
+            return true
+        }
+
+        if end + lateness <= watermark {
+            return false
+        }
 
+        // Late tick may be the first one of its period.
+        let candle = Candle::new(info, rate, start, duration);
+        if end <= watermark {
+            done.push(candle.ohlc.clone());
+            if self.last.as_ref().is_none_or(|last| last.start < start) {
+                self.last = Some(candle.ohlc.clone());
             }
+            self.finished.insert(start, candle);
+            self.refill(start, done);
         }
-        // Update OHLC values accordingly.
         else {
-            if ohlc.high < rate {
-                ohlc.high = rate;
+            self.open.insert(start, candle);
+        }
+
+        true
+    }
+
+
+
+    /// Synthetic Ohlc that follow amended Ohlc at `start` repeat its old
+    /// close, they are replaced with new close and sent to storage again.
+    fn refill(&mut self, start: u64, done: &mut Vec<Ohlc>) {
+        let Some(mut prev) = self.finished.get(&start).map(|c| c.ohlc.clone())
+        else {
+            return
+        };
+
+        for candle in self.finished.range_mut(start + 1..).map(|(_, c)| c) {
+            // Real Ohlc ends gap, the rest of gap already has the same close.
+            if !candle.ohlc.synthetic || candle.ohlc.close == prev.close {
+                break
             }
 
-            if ohlc.low > rate {
-                ohlc.low = rate;
+            let revision = candle.ohlc.revision;
+            *candle = Candle::synthetic(&prev, candle.ohlc.start);
+            candle.ohlc.revision = revision + 1;
+            done.push(candle.ohlc.clone());
+
+            prev = candle.ohlc.clone();
+        }
+
+        if self.last.as_ref().is_some_and(|last| last.start == prev.start) {
+            self.last = Some(prev);
+        }
+    }
+
+
+
+    /// Finish Ohlc whose period has been reached by watermark, periods
+    /// without ticks get synthetic Ohlc, but no more than `gap_fill` in a
+    /// row. Forget finished Ohlc that can not be amended anymore.
+    fn advance(&mut self, duration: u32, watermark: u64, lateness: u64,
+        gap_fill: u32, done: &mut Vec<Ohlc>
+    )
+    {
+        let duration_u64 = duration as u64;
+
+        loop {
+            // The oldest open period or the one after the newest finished,
+            // whichever comes first.
+            let open = self.open.keys().next().copied();
+            let gap = self.last.as_ref()
+                .filter(|_| self.filled < gap_fill)
+                .map(|last| last.start + duration_u64);
+
+            let start = match (open, gap) {
+                (Some(open), Some(gap)) => open.min(gap),
+                (Some(start), None) | (None, Some(start)) => start,
+                (None, None) => break,
+            };
+
+            if start + duration_u64 > watermark {
+                break
             }
 
-            // Any value is considered a close, because we do not know if we
-            // will get data for the same minute in next message.
-            ohlc.close = rate;
+            let candle = match (self.open.remove(&start), &self.last) {
+                (Some(candle), _) => candle,
+                (None, Some(last)) => Candle::synthetic(last, start),
+                (None, None) => break,
+            };
+
+            self.filled = if candle.ohlc.synthetic { self.filled + 1 } else { 0 };
+
+            done.push(candle.ohlc.clone());
+            self.last = Some(candle.ohlc.clone());
+            self.finished.insert(start, candle);
+        }
+
+        self.finished.retain(|start, _| {
+            start + duration_u64 + lateness > watermark
+        });
+    }
+
+
+
+    /// Finish all open Ohlc, because no more ticks will come.
+    fn flush(&mut self, duration: u32, lateness: u64, gap_fill: u32,
+        done: &mut Vec<Ohlc>
+    )
+    {
+        let Some(start) = self.open.keys().next_back().copied() else {
+            return
+        };
+
+        self.advance(duration, start + duration as u64, lateness, gap_fill, done);
+    }
+
+
+
+    /// The newest Ohlc, it is shown in terminal.
+    fn current(&self) -> Option<&Ohlc> {
+        self.open.values().next_back()
+            .or_else(|| self.finished.values().next_back())
+            .map(|candle| &candle.ohlc)
+    }
+}
+
+
+
+/// Send finished Ohlc to storage. Live Ohlc are dropped if storage can not
+/// keep up, history waits for storage.
+async fn storage_send(calc: &OhlcCalc, done: &mut Vec<Ohlc>,
+    metric: &impl Fn(String)
+)
+{
+    for ohlc in done.drain(..) {
+        if ohlc.revision > 0 {
+            metric(format!("ohlc_amended_total{{pair=\"{}/{}\"}}",
+                ohlc.base, ohlc.quote
+            ));
+        }
+
+        if calc.history {
+            if calc.tx_storage.send(ohlc).await.is_err() {
+                eprintln!("ERROR: storage has stopped, dropping Ohlc.");
+            }
+
+            continue
+        }
+
+        // Loose data if DB backend can not keep up.
+        if calc.tx_storage.try_send(ohlc).is_err() {
+            eprintln!(concat!("Storage backend can not keep up with",
+                " generated data. dropping Ohlc."
+            ));
+        }
+    }
+}
+
+
+
+/// What calc has been woken up by.
+enum Event {
+    Tick(PriceInfo),
+    Timer,
+}
+
+
+
+pub async fn main(mut calc: OhlcCalc, shared_state: Arc<SharedState>) {
+    // Each pair and duration has its own Ohlc, so that data from different
+    // assets never ends up in the same candle.
+    let mut series: BTreeMap<(Symbol, Symbol, u32), Series> = BTreeMap::new();
+    let mut watermarks: HashMap<(Symbol, Symbol), u64> = HashMap::new();
+    let mut done = Vec::new();
+
+    let mut terminal_ohlc: Box<Option<OhlcMap>> = Box::new(None);
+    let mut ts_snapshot: Option<Instant> = None;
+    let mut ts_finalize = calc.clock.instant() + FINALIZE_PERIOD;
+
+    let snapshot = |series: &BTreeMap<(Symbol, Symbol, u32), Series>| {
+        series.iter()
+            .filter_map(|(key, s)| s.current().map(|ohlc| (*key, ohlc.clone())))
+            .collect::<OhlcMap>()
+    };
+
+    let metric = |name: String| {
+        shared_state.metrics.get(&name).fetch_add(1, Ordering::Relaxed);
+    };
+
+    let gap_fill = if calc.gap_fill { calc.gap_fill_max } else { 0 };
+
+    loop {
+        let event = match calc.grace {
+            Some(..) => tokio::select! {
+                info = calc.rx.recv() => info.map(Event::Tick),
+                _ = calc.clock.sleep_until(ts_finalize) => Some(Event::Timer),
+            },
+            None => calc.rx.recv().await.map(Event::Tick),
+        };
+
+        // If collector thread has crashed, this thread has no use to be alive.
+        // History has ended, so Ohlc that are still open are complete.
+        let Some(event) = event else {
+            if calc.history {
+                for ((_, _, duration), s) in series.iter_mut() {
+                    s.flush(*duration, calc.lateness, gap_fill, &mut done);
+                }
+                storage_send(&calc, &mut done, &metric).await;
+            }
+
+            break
+        };
+
+        match event {
+            Event::Tick(info) => {
+                let Some(rate) = info.rate else { continue };
+
+                // Sources may provide any number of decimal places, Ohlc uses
+                // fixed scale.
+                let rescaled = rate.rescale_round(Ohlc::SCALE, calc.rounding);
+                let rate = match rescaled {
+                    Ok(rate) => rate,
+                    Err(e) => {
+                        eprintln!(concat!("ERROR: could not normalize {}/{}",
+                            " rate {}, dropping rate, error: {}"
+                        ), info.base, info.quote, rate, e);
+
+                        continue
+                    }
+                };
+
+                let watermark = watermarks.entry((info.base, info.quote))
+                    .or_default();
+                *watermark = (*watermark).max(info.timestamp);
+                let watermark = *watermark;
+
+                for duration in &calc.timeframes {
+                    let s = series.entry((info.base, info.quote, *duration))
+                        .or_default();
+
+                    let routed = s.update(&info, rate, *duration, watermark,
+                        calc.lateness, &mut done
+                    );
+                    if !routed {
+                        metric(format!(concat!("ohlc_late_dropped_total",
+                            "{{pair=\"{}/{}\",duration=\"{}\"}}"
+                        ), info.base, info.quote, duration));
+                    }
+
+                    s.advance(*duration, watermark, calc.lateness, gap_fill,
+                        &mut done
+                    );
+                }
+            }
+
+            // Source may stall, so watermark follows wall clock as well.
+            Event::Timer => {
+                ts_finalize = calc.clock.instant() + FINALIZE_PERIOD;
+
+                let grace = calc.grace.unwrap_or(0);
+                let wall = calc.clock.now().duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+                    .saturating_sub(grace);
+
+                for ((base, quote, duration), s) in series.iter_mut() {
+                    let watermark = watermarks.entry((*base, *quote))
+                        .or_default();
+                    *watermark = (*watermark).max(wall);
+
+                    s.advance(*duration, *watermark, calc.lateness, gap_fill,
+                        &mut done
+                    );
+                }
+            }
         }
 
-        *terminal_ohlc = Some(ohlc.clone());
-        terminal_ohlc = calc.terminal.swap(terminal_ohlc);
+        storage_send(&calc, &mut done, &metric).await;
+
+        let now = calc.clock.instant();
+        let publish = match ts_snapshot {
+            Some(ts) => now - ts >= SNAPSHOT_PERIOD,
+            None => true,
+        };
+
+        if publish {
+            *terminal_ohlc = Some(snapshot(&series));
+            terminal_ohlc = calc.terminal.swap(terminal_ohlc);
+            ts_snapshot = Some(now);
+        }
 
         let intr = shared_state.shut_down.load(Ordering::Relaxed);
         if intr != 0 {
-            return
+            break
         }
     }
+
+    // Ticks that arrived after last snapshot must be shown as well.
+    *terminal_ohlc = Some(snapshot(&series));
+    calc.terminal.swap(terminal_ohlc);
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use rate_limit::clock::MockClock;
+
+    #[test]
+    fn test_timeframes_parse() {
+        assert_eq!(timeframes_parse("1m, 5m,15m,1h,1d,30s,90").unwrap(),
+            vec![60, 300, 900, 3600, 86400, 30, 90]
+        );
+        assert!(timeframes_parse("0m").is_err());
+        assert!(timeframes_parse("1w").is_err());
+        assert!(timeframes_parse("m").is_err());
+    }
+
+    // Feed ticks to series of given durations, returns Ohlc sent to storage
+    // and ticks that were dropped.
+    fn feed(series: &mut BTreeMap<u32, Series>, ticks: &[(u64, u64)],
+        lateness: u64, gap_fill: u32
+    )
+        -> (Vec<Ohlc>, usize)
+    {
+        let btc = Symbol::new("BTC").unwrap();
+        let mut done = Vec::new();
+        let mut dropped = 0;
+
+        for (ts, rate) in ticks {
+            let watermark = series.values()
+                .flat_map(|s| s.open.values().chain(s.finished.values()))
+                .map(|c| c.ohlc.ts_last)
+                .fold(*ts, u64::max);
+
+            let rate = Price::new(*rate, Ohlc::SCALE).unwrap();
+            let info = PriceInfo::new(*ts, btc, Symbol::USD, Some(rate));
+
+            for (duration, s) in series.iter_mut() {
+                let routed = s.update(&info, rate, *duration, watermark,
+                    lateness, &mut done
+                );
+                if !routed {
+                    dropped += 1;
+                }
+                s.advance(*duration, watermark, lateness, gap_fill, &mut done);
+            }
+        }
+
+        (done, dropped)
+    }
+
+    #[test]
+    fn test_ohlc_update_timeframes() {
+        let mut series = BTreeMap::from([(60, Series::default()),
+            (300, Series::default())
+        ]);
+
+        // Ticks at 00:00:10, 00:00:50, 00:01:10 and 00:05:00 UTC.
+        let (finished, _) = feed(&mut series,
+            &[(10, 100), (50, 120), (70, 90), (300, 110)], 0, 0
+        );
+
+        let ohlc_1m: Vec<_> = finished.iter()
+            .filter(|o| o.duration == 60)
+            .map(|o| (o.start, o.open.value(), o.high.value(), o.low.value(),
+                o.close.value()
+            ))
+            .collect();
+        assert_eq!(ohlc_1m, vec![(0, 100, 120, 100, 120), (60, 90, 90, 90, 90)]);
+
+        let ohlc_5m: Vec<_> = finished.iter()
+            .filter(|o| o.duration == 300)
+            .map(|o| (o.start, o.open.value(), o.high.value(), o.low.value(),
+                o.close.value()
+            ))
+            .collect();
+        assert_eq!(ohlc_5m, vec![(0, 100, 120, 90, 90)]);
+
+        assert_eq!(series[&60].current().unwrap().start, 300);
+        assert_eq!(series[&300].current().unwrap().start, 300);
+    }
+
+    /// Late ticks go to Ohlc of their own period, finished Ohlc is sent again
+    /// with new revision, ticks later than allowed lateness are dropped.
+    #[test]
+    fn test_late_ticks() {
+        let mut series = BTreeMap::from([(60, Series::default())]);
+        let row = |o: &Ohlc| (o.start, o.revision, o.open.value(),
+            o.high.value(), o.low.value(), o.close.value()
+        );
+
+        // Tick of older second within the same minute does not replace close,
+        // late tick does not reset current minute.
+        let (done, dropped) = feed(&mut series,
+            &[(70, 100), (65, 90), (100, 110), (20, 50), (130, 120)], 60, 0
+        );
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (0, 0, 50, 50, 50, 50),
+            (60, 0, 90, 110, 90, 110),
+        ]);
+        assert_eq!(dropped, 0);
+
+        // Finished Ohlc is amended, the one past lateness is forgotten.
+        let (done, dropped) = feed(&mut series, &[(110, 130), (50, 40)], 60, 0);
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (60, 1, 90, 130, 90, 130),
+        ]);
+        assert_eq!(dropped, 1);
+        assert_eq!(series[&60].current().unwrap().start, 120);
+
+        // Without lateness any tick of finished period is dropped.
+        let mut series = BTreeMap::from([(60, Series::default())]);
+        let (done, dropped) = feed(&mut series, &[(10, 100), (70, 90), (50, 80)],
+            0, 0
+        );
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (0, 0, 100, 100, 100, 100),
+        ]);
+        assert_eq!(dropped, 1);
+    }
+
+    /// Averages are kept up to date, late tick between known ticks does not
+    /// change TWAP.
+    #[test]
+    fn test_stats() {
+        let btc = Symbol::new("BTC").unwrap();
+        let tick = |ts, rate, volume: Option<u64>| {
+            let mut info = PriceInfo::new(ts, btc, Symbol::USD,
+                Some(Price::new(rate, Ohlc::SCALE).unwrap())
+            );
+            info.volume = volume.map(|v| Price::new(v, 0).unwrap());
+            info
+        };
+
+        let info = tick(0, 100, Some(1));
+        let mut candle = Candle::new(&info, info.rate.unwrap(), 0, 60);
+        for info in [tick(30, 110, Some(3)), tick(45, 120, None),
+            tick(10, 90, Some(2))
+        ] {
+            candle.update(&info, info.rate.unwrap());
+        }
+
+        let o = &candle.ohlc;
+        assert_eq!((o.ticks, o.ts_first, o.ts_last), (4, 0, 45));
+        assert_eq!((o.open.value(), o.close.value()), (100, 120));
+        assert_eq!((o.mean.value(), o.twap.value()), (105, 108));
+        assert_eq!(o.volume.map(|v| v.value()), Some(600000000));
+        assert_eq!(o.vwap.map(|v| v.value()), Some(102));
+
+        // Synthetic Ohlc has no ticks, averages are previous close.
+        let o = Candle::synthetic(o, 60).ohlc;
+        assert_eq!((o.ticks, o.mean.value(), o.twap.value(), o.volume),
+            (0, 120, 120, None)
+        );
+    }
+
+    /// Periods without ticks get flat synthetic Ohlc, late tick replaces it.
+    #[test]
+    fn test_gap_fill() {
+        let mut series = BTreeMap::from([(60, Series::default())]);
+        let row = |o: &Ohlc| (o.start, o.revision, o.synthetic, o.open.value(),
+            o.close.value()
+        );
+
+        let (done, _) = feed(&mut series, &[(10, 100), (70, 110), (250, 120)],
+            120, 10
+        );
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (0, 0, false, 100, 100),
+            (60, 0, false, 110, 110),
+            (120, 0, true, 110, 110),
+            (180, 0, true, 110, 110),
+        ]);
+
+        // Synthetic Ohlc after replaced one get its close.
+        let (done, _) = feed(&mut series, &[(130, 90)], 120, 10);
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (120, 1, false, 90, 90),
+            (180, 1, true, 90, 90),
+        ]);
+
+        // Tick before the last one does not change close, nothing to refill.
+        let (done, _) = feed(&mut series, &[(125, 80)], 120, 10);
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (120, 2, false, 80, 90),
+        ]);
+
+        // Amended real Ohlc has new close.
+        let (done, _) = feed(&mut series, &[(170, 95)], 120, 10);
+        assert_eq!(done.iter().map(row).collect::<Vec<_>>(), vec![
+            (120, 3, false, 80, 95),
+            (180, 2, true, 95, 95),
+        ]);
+    }
+
+    /// Long gap, i.e. days of 1 minute periods, gets only limited number of
+    /// synthetic Ohlc, the next real Ohlc is finished as usual.
+    #[test]
+    fn test_gap_fill_max() {
+        let mut series = BTreeMap::from([(60, Series::default())]);
+        let day = 86400;
+
+        let (done, _) = feed(&mut series,
+            &[(10, 100), (3 * day + 10, 110), (3 * day + 70, 120)], 0, 5
+        );
+        assert_eq!(done.iter().map(|o| (o.start, o.synthetic)).collect::<Vec<_>>(),
+            vec![(0, false), (60, true), (120, true), (180, true), (240, true),
+                (300, true), (3 * day, false)
+            ]
+        );
+
+        // Gap after real Ohlc is filled again.
+        let (done, _) = feed(&mut series, &[(3 * day + 250, 130)], 0, 5);
+        assert_eq!(done.iter().map(|o| (o.start, o.synthetic)).collect::<Vec<_>>(),
+            vec![(3 * day + 60, false), (3 * day + 120, true),
+                (3 * day + 180, true)
+            ]
+        );
+    }
+
+    /// Timer finishes Ohlc once wall clock is grace period past its end,
+    /// even if no more ticks arrive.
+    #[tokio::test(start_paused = true)]
+    async fn test_finalize_timer() {
+        let btc = Symbol::new("BTC").unwrap();
+        let (tx, rx) = mpsc::channel(10);
+        let (tx_storage, mut rx_storage) = mpsc::channel::<Ohlc>(10);
+        let terminal = Arc::new(AtomicSwap::new(Box::new(None)));
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(600)));
+
+        let mut calc = OhlcCalc::new(rx, tx_storage, terminal);
+        calc.clock_set(clock.clone());
+        calc.grace_set(5);
+        calc.gap_fill_set(true);
+
+        let calc_h = tokio::spawn(main(calc, Arc::new(SharedState::default())));
+
+        let rate = Price::new(100, 0).unwrap();
+        tx.send(PriceInfo::new(610, btc, Symbol::USD, Some(rate))).await
+            .unwrap();
+
+        let ohlc = rx_storage.recv().await.unwrap();
+        assert_eq!((ohlc.start, ohlc.synthetic, ohlc.close.value()),
+            (600, false, 1000000)
+        );
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(665));
+
+        let ohlc = rx_storage.recv().await.unwrap();
+        assert_eq!((ohlc.start, ohlc.synthetic, ohlc.close.value()),
+            (660, true, 1000000)
+        );
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(725));
+
+        drop(tx);
+        calc_h.await.unwrap();
+    }
+
+    /// Rates are rounded to Ohlc::SCALE by configured rounding mode. History
+    /// Ohlc is stored once input ends.
+    #[tokio::test]
+    async fn test_rounding() {
+        let btc = Symbol::new("BTC").unwrap();
+
+        // Rates with 5 decimal places and expected close with 4.
+        for (rounding, rate, close) in [(Rounding::Down, 123459, 12345),
+            (Rounding::Up, 123451, 12346), (Rounding::HalfUp, 123455, 12346),
+            (Rounding::HalfEven, 123455, 12346), (Rounding::HalfEven, 123465, 12346)
+        ] {
+            let (tx, rx) = mpsc::channel(10);
+            let (tx_storage, mut rx_storage) = mpsc::channel::<Ohlc>(10);
+            let terminal = Arc::new(AtomicSwap::new(Box::new(None)));
+
+            let mut calc = OhlcCalc::new(rx, tx_storage, terminal);
+            calc.history_set(true);
+            calc.rounding_set(rounding);
+
+            let calc_h = tokio::spawn(main(calc, Arc::new(SharedState::default())));
+
+            let rate = Price::new(rate, 5).unwrap();
+            tx.send(PriceInfo::new(10, btc, Symbol::USD, Some(rate))).await
+                .unwrap();
+            drop(tx);
+            calc_h.await.unwrap();
+
+            let ohlc = rx_storage.recv().await.unwrap();
+            assert_eq!(ohlc.close.value(), close, "{:?}", rounding);
+        }
+    }
+
+    /// Terminal snapshot is published at most once per SNAPSHOT_PERIOD and
+    /// once more when calc stops.
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_period() {
+        let btc = Symbol::new("BTC").unwrap();
+        let (tx, rx) = mpsc::channel(10);
+        let (tx_storage, _rx_storage) = mpsc::channel::<Ohlc>(10);
+        let terminal = Arc::new(AtomicSwap::new(Box::new(None)));
+
+        let mut calc = OhlcCalc::new(rx, tx_storage, terminal.clone());
+        calc.clock_set(Arc::new(MockClock::new(UNIX_EPOCH)));
+
+        let calc_h = tokio::spawn(main(calc, Arc::new(SharedState::default())));
+
+        // Take snapshot, if any, once calc has processed all sent ticks.
+        let snapshot = || async {
+            while tx.capacity() < tx.max_capacity() {
+                tokio::task::yield_now().await;
+            }
+            tokio::task::yield_now().await;
+
+            terminal.swap(Box::new(None))
+                .map(|ohlcs| ohlcs[&(btc, Symbol::USD, 60)].close.value())
+        };
+
+        for (rate, advance, published) in [(100, 0, Some(100)), (101, 50, None),
+            (102, 60, Some(102))
+        ] {
+            tokio::time::advance(Duration::from_millis(advance)).await;
+
+            let rate = Price::new(rate, 0).unwrap();
+            tx.send(PriceInfo::new(10, btc, Symbol::USD, Some(rate))).await
+                .unwrap();
+
+            assert_eq!(snapshot().await, published.map(|v| v * 10000));
+        }
+
+        tx.send(PriceInfo::new(20, btc, Symbol::USD,
+            Some(Price::new(103, 0).unwrap())
+        )).await.unwrap();
+        drop(tx);
+        calc_h.await.unwrap();
+
+        let ohlcs = terminal.swap(Box::new(None)).unwrap();
+        assert_eq!(ohlcs[&(btc, Symbol::USD, 60)].close.value(), 1030000);
+    }
+}
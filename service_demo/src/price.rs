@@ -0,0 +1,266 @@
+//! Fixed-point price representation.
+//!
+//! Prices are stored as whole numbers with explicit scale, because floating
+//! point looses precission. Different sources may provide rates with different
+//! number of decimal places, so before rates can be compared or aggregated
+//! they must be rescaled to common scale.
+
+
+
+use std::{
+    cmp::Ordering,
+    fmt,
+};
+
+use crate::decimal::Rounding;
+
+
+
+/// Largest scale that can be represented, since 10^19 is the largest power
+/// of 10 that fits into u64.
+pub const SCALE_MAX: u8 = 19;
+
+
+
+/// Fixed-point price.
+///
+/// `value` - price multiplied by 10^scale, i.e. value 20342 with scale 3 is
+/// 20.342.
+/// `scale` - number of decimal places in value.
+#[derive(Debug, Clone, Copy)]
+pub struct Price {
+    value: u64,
+    scale: u8,
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceError {
+    /// Price does not fit into u64 at requested scale.
+    Overflow,
+    /// Requested scale is larger than SCALE_MAX.
+    ScaleInvalid(u8),
+}
+
+
+
+impl fmt::Display for PriceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "price does not fit into u64"),
+            Self::ScaleInvalid(scale) => write!(f, "price scale {} is larger than {}",
+                scale, SCALE_MAX
+            ),
+        }
+    }
+}
+
+
+
+#[inline]
+fn pow10(exp: u8) -> u64 {
+    10u64.pow(exp as u32)
+}
+
+
+
+impl Price {
+    pub fn new(value: u64, scale: u8) -> Result<Self, PriceError> {
+        if scale > SCALE_MAX {
+            return Err(PriceError::ScaleInvalid(scale))
+        }
+
+        Ok(Self { value, scale })
+    }
+
+
+
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+
+
+    /// Convert price to different scale.
+    ///
+    /// When scale is increased, value is multiplied and overflow is reported
+    /// as error. When scale is decreased, value is rounded half up, i.e.
+    /// 1.25 with scale 1 becomes 1.3.
+    pub fn rescale(&self, scale: u8) -> Result<Self, PriceError> {
+        self.rescale_round(scale, Rounding::HalfUp)
+    }
+
+
+
+    /// Convert price to different scale, same as Price::rescale, but value
+    /// is rounded by given mode when scale is decreased.
+    pub fn rescale_round(&self, scale: u8, rounding: Rounding)
+        -> Result<Self, PriceError>
+    {
+        if scale > SCALE_MAX {
+            return Err(PriceError::ScaleInvalid(scale))
+        }
+
+        let value = match scale.cmp(&self.scale) {
+            Ordering::Equal => self.value,
+
+            Ordering::Greater => {
+                let mul = pow10(scale - self.scale);
+
+                self.value.checked_mul(mul).ok_or(PriceError::Overflow)?
+            }
+
+            Ordering::Less => {
+                let div = pow10(self.scale - scale);
+                let value = self.value / div;
+                let rem = self.value % div;
+
+                // Compare remainder against half of divisor without overflowing
+                // on largest divisors.
+                let round_up = match rounding {
+                    Rounding::Down => false,
+                    Rounding::Up => rem > 0,
+                    Rounding::HalfUp => rem >= div - rem,
+                    Rounding::HalfEven => {
+                        rem > div - rem || (rem == div - rem && value % 2 == 1)
+                    }
+                };
+
+                if round_up {
+                    value.checked_add(1).ok_or(PriceError::Overflow)?
+                }
+                else {
+                    value
+                }
+            }
+        };
+
+        Ok(Self { value, scale })
+    }
+
+
+
+    // Value at common scale, so that prices with different scales can be
+    // compared. Product of two u64 values always fits into u128.
+    #[inline]
+    fn value_at(&self, scale: u8) -> u128 {
+        self.value as u128 * pow10(scale - self.scale) as u128
+    }
+}
+
+
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+
+
+impl Eq for Price {}
+
+
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.scale == other.scale {
+            return self.value.cmp(&other.value)
+        }
+
+        let scale = self.scale.max(other.scale);
+
+        self.value_at(scale).cmp(&other.value_at(scale))
+    }
+}
+
+
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scale == 0 {
+            return write!(f, "{}", self.value)
+        }
+
+        let div = pow10(self.scale);
+
+        write!(f, "{}.{:0width$}", self.value / div, self.value % div,
+            width = self.scale as usize
+        )
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rescale() {
+        let p = Price::new(123456, 4).unwrap();
+
+        assert_eq!(p.rescale(4).unwrap().value(), 123456);
+        assert_eq!(p.rescale(6).unwrap().value(), 12345600);
+        assert_eq!(p.rescale(2).unwrap().value(), 1235);
+        assert_eq!(p.rescale(1).unwrap().value(), 123);
+        assert_eq!(p.rescale(0).unwrap().value(), 12);
+
+        let p = Price::new(u64::MAX, 0).unwrap();
+        assert_eq!(p.rescale(1), Err(PriceError::Overflow));
+
+        let p = Price::new(u64::MAX, SCALE_MAX).unwrap();
+        assert_eq!(p.rescale(0).unwrap().value(), 2);
+        assert_eq!(p.rescale(SCALE_MAX + 1), Err(PriceError::ScaleInvalid(20)));
+    }
+
+    #[test]
+    fn test_rescale_round() {
+        let cases = [
+            // value, Down, Up, HalfUp, HalfEven
+            (1234540, 12345, 12346, 12345, 12345),
+            (1234550, 12345, 12346, 12346, 12346),
+            (1234650, 12346, 12347, 12347, 12346),
+            (1234651, 12346, 12347, 12347, 12347),
+            (1234600, 12346, 12346, 12346, 12346),
+        ];
+
+        for (value, down, up, half_up, half_even) in cases {
+            let p = Price::new(value, 6).unwrap();
+            let round = |rounding| p.rescale_round(4, rounding).unwrap().value();
+
+            assert_eq!(round(Rounding::Down), down, "{}", value);
+            assert_eq!(round(Rounding::Up), up, "{}", value);
+            assert_eq!(round(Rounding::HalfUp), half_up, "{}", value);
+            assert_eq!(round(Rounding::HalfEven), half_even, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_cmp_display() {
+        let a = Price::new(12345, 3).unwrap();
+        let b = Price::new(123450, 4).unwrap();
+        let c = Price::new(12346, 3).unwrap();
+
+        assert_eq!(a, b);
+        assert!(b < c);
+        assert_eq!(a.to_string(), "12.345");
+        assert_eq!(Price::new(5, 4).unwrap().to_string(), "0.0005");
+        assert_eq!(Price::new(42, 0).unwrap().to_string(), "42");
+    }
+}
@@ -1,43 +1,45 @@
 
 
 
+use crate::{
+    price::Price,
+    symbol::Symbol,
+};
+
+
+
 /// Normalized price information structure that can be used for various crypto
 /// and currency pairs.
 ///
 /// `base` - this is the first currency that is shown in pair, i.e. BTC/USD,
 /// base is BTC.
 /// `quote` - is the second shown in pair, i.e. BTC/USD, USD is quote.
-/// `rate` - we use whole numbers to store rate because floating point looses
-/// precission. Rate contains value that must be divided by 10^decimal.
-/// `decimal` - number of decimal numbers in rate value, i.e. if decimal is 3,
-/// then rate 20342 real value is 20.342.
+/// `rate` - fixed-point rate with scale as provided by source, it must be
+/// rescaled before it is compared with rates from other sources.
+/// `volume` - traded amount of base, if source supplies it, i.e. quantity of
+/// trade.
+/// `sources` - names of sources whose rates were combined into this one by
+/// consensus, empty if rate comes directly from single source.
 #[derive(Debug, Clone)]
 pub struct PriceInfo {
     pub timestamp: u64,
     pub base: Symbol,
     pub quote: Symbol,
-    pub rate: Option<u64>,
-    pub decimal: u8,
-}
-
-
-
-#[derive(Debug, Clone)]
-pub enum Symbol {
-    BTC,
-    USD,
+    pub rate: Option<Price>,
+    pub volume: Option<Price>,
+    pub sources: Vec<String>,
 }
 
 
 
 impl PriceInfo {
-    pub fn new(timestamp: u64, base: Symbol, quote: Symbol, rate: Option<u64>,
-        decimal: u8
-    )
+    pub fn new(timestamp: u64, base: Symbol, quote: Symbol, rate: Option<Price>)
         -> Self
     {
         Self {
-            timestamp, base, quote, rate, decimal,
+            timestamp, base, quote, rate,
+            volume: None,
+            sources: Vec::new(),
         }
     }
 }
@@ -1,5 +1,7 @@
 use std::sync::atomic::AtomicUsize;
 
+use crate::metrics::Metrics;
+
 
 
 #[derive(Default)]
@@ -10,6 +12,9 @@ pub struct SharedState {
     // If some synchronous task is sleeping, it will take this into account only
     // when the thread is waken up.
     pub shut_down: AtomicUsize,
+
+    // Runtime metrics of all tasks.
+    pub metrics: Metrics,
 }
 
 
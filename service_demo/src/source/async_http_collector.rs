@@ -0,0 +1,867 @@
+//! Collector that polls rates from REST endpoint over HTTP.
+//!
+//! Polling loop and rate limiting are shared by all REST endpoints, while
+//! response format is described by Schema implementation, see coincap.rs and
+//! binance.rs.
+//!
+//! Requests are scheduled with monotonic time, wall time is used only for
+//! rate limits announced by endpoint.
+//!
+//! Collector does not stop on failed request, next request is delayed
+//! according to retry policy, see retry.rs.
+//!
+//! Requests are conditional if endpoint returns ETag or Last-Modified, 304
+//! Not Modified means that there is no new data. Rates whose timestamp is not
+//! newer than timestamp of the last forwarded rate are dropped as duplicates.
+//! Timestamps have second resolution, so rate that has changed within the
+//! same second is dropped as well and, like any duplicate, it is a miss for
+//! adaptive polling.
+//!
+//! Requests are made with fixed period, or, in adaptive mode, shortly after
+//! upstream is expected to update, see cadence.rs.
+
+
+
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use tokio::sync::mpsc;
+
+use reqwest::{
+    Client,
+    RequestBuilder,
+    StatusCode,
+    header::{
+        ETAG,
+        HeaderMap,
+        HeaderValue,
+        IF_MODIFIED_SINCE,
+        IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+};
+
+use async_trait::async_trait;
+
+use rate_limit::{
+    RateLimit,
+    bucket::TokenBucket,
+    clock::{
+        self,
+        Clock,
+        SystemClock,
+    },
+    skew::ClockSkew,
+};
+
+use crate::{
+    shared_state::SharedState,
+    http_client::HttpConfig,
+    price_info::PriceInfo,
+    decimal::{
+        DecimalError,
+        Rounding,
+    },
+    source::{
+        Source,
+        cadence::{
+            Cadence,
+            CadencePolicy,
+        },
+        retry::{
+            BreakerState,
+            ErrorClass,
+            Retry,
+            RetryPolicy,
+        },
+    },
+    symbol::{
+        Asset,
+        Symbol,
+        SymbolError,
+    },
+};
+
+
+
+/// How often shut down flag is checked while waiting for next request.
+const CHECK_PERIOD: Duration = Duration::from_millis(250);
+
+
+
+/// Describes request URL and response format of REST rates endpoint.
+pub trait Schema: Send + Sync + 'static {
+    /// Request URL for given asset, `url` is endpoint URL from configuration.
+    fn url(&self, url: &str, asset: &Asset) -> String;
+
+    /// Decode response body into PriceInfo.
+    ///
+    /// `base` - configured asset symbol, if it is None, symbol must be taken
+    /// from response.
+    /// `skew` - server clock offset, timestamps returned by endpoint must be
+    /// converted to local clock with it.
+    /// `clock` - source of local time, if endpoint does not return timestamp.
+    fn decode(&self, body: &str, base: Option<Symbol>, rounding: Rounding,
+        skew: &ClockSkew, clock: &dyn Clock
+    )
+        -> Result<PriceInfo, DecodeError>;
+
+    /// Whether decoded timestamps come from endpoint. If endpoint does not
+    /// return timestamps, local time is used and duplicates can not be
+    /// recognized by timestamp.
+    fn upstream_timestamp(&self) -> bool {
+        true
+    }
+}
+
+
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    Symbol(SymbolError),
+    Rate(DecimalError),
+    /// Response does not contain requested pair.
+    Pair(String),
+}
+
+
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "could not decode response as JSON: {}", e),
+            Self::Symbol(e) => write!(f, "invalid symbol: {}", e),
+            Self::Rate(e) => write!(f, "invalid rate: {}", e),
+            Self::Pair(pair) => write!(f, "unexpected pair: {}", pair),
+        }
+    }
+}
+
+
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+
+
+impl From<SymbolError> for DecodeError {
+    fn from(e: SymbolError) -> Self {
+        Self::Symbol(e)
+    }
+}
+
+
+
+impl From<DecimalError> for DecodeError {
+    fn from(e: DecimalError) -> Self {
+        Self::Rate(e)
+    }
+}
+
+
+
+/// Failure of single request.
+#[derive(Debug)]
+pub enum CollectError {
+    /// Request could not be made or response could not be read, i.e. DNS
+    /// failure, connection reset or timeout.
+    Transport(reqwest::Error),
+    /// Endpoint returned status other than 200.
+    Status(StatusCode),
+    Decode(DecodeError),
+    /// Decoded rate can not be used, i.e. it is zero.
+    Validation(String),
+}
+
+
+
+impl CollectError {
+    /// Transport errors and server errors are expected to go away soon, other
+    /// errors need endpoint or configuration to be fixed.
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::Transport(..) => ErrorClass::Transient,
+            Self::Status(status) if status.is_server_error()
+                || *status == StatusCode::REQUEST_TIMEOUT
+                || *status == StatusCode::TOO_MANY_REQUESTS
+                => ErrorClass::Transient,
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+
+
+impl fmt::Display for CollectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "transport error: {}", e),
+            Self::Status(status) => write!(f, "unexpected status: {}", status),
+            Self::Decode(e) => write!(f, "could not decode response: {}", e),
+            Self::Validation(e) => write!(f, "invalid data: {}", e),
+        }
+    }
+}
+
+
+
+impl From<reqwest::Error> for CollectError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Transport(e)
+    }
+}
+
+
+
+impl From<DecodeError> for CollectError {
+    fn from(e: DecodeError) -> Self {
+        Self::Decode(e)
+    }
+}
+
+
+
+/// Cache validators of the last accepted response, they are sent with next
+/// request, so that endpoint can answer 304 Not Modified.
+#[derive(Debug, Default, Clone)]
+struct Validators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+
+
+impl Validators {
+    fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(ref etag) = self.etag {
+            req = req.header(IF_NONE_MATCH, etag.clone());
+        }
+
+        if let Some(ref last_modified) = self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        req
+    }
+
+
+
+    fn update(&mut self, hm: &HeaderMap) {
+        self.etag = hm.get(ETAG).cloned();
+        self.last_modified = hm.get(LAST_MODIFIED).cloned();
+    }
+}
+
+
+
+/// Collector that polls single asset rate from HTTP endpoint.
+///
+/// `schema` - request URL and response format of the endpoint.
+/// `base` - symbol of the asset that is requested from `url`, every PriceInfo
+/// sent by this collector is tagged with it. If it is None, symbol returned by
+/// endpoint is used.
+/// `rounding` - rounding mode used if rate has more decimal places than fit
+/// into Price.
+/// `bucket` - local rate limiting policy, usually shared by all collectors
+/// that request the same host.
+/// `clock` - source of time for scheduling and rate limiting.
+/// `skew` - server clock offset, it is estimated from responses and used to
+/// convert endpoint timestamps to local clock.
+/// `retry` - backoff and circuit breaker settings for failed requests.
+/// `client` - HTTP client, collectors should share clones of single client,
+/// so that connections are pooled.
+/// `cadence` - adaptive polling policy, if it is set, request period is
+/// learned from upstream update cadence instead of fixed `request_period`.
+pub struct AsyncHTTPCollector<S> {
+    schema: S,
+    tx: mpsc::Sender<PriceInfo>,
+    url: String,
+    base: Option<Symbol>,
+    request_period: u64,
+    rounding: Rounding,
+    bucket: Option<Arc<TokenBucket>>,
+    clock: Arc<dyn Clock>,
+    skew: Arc<ClockSkew>,
+    retry: RetryPolicy,
+    client: Client,
+    cadence: Option<CadencePolicy>,
+}
+
+
+
+impl<S: Schema> AsyncHTTPCollector<S> {
+    /// Create collector for given asset, `url` is endpoint URL from
+    /// configuration, request URL is built by schema.
+    pub fn new(schema: S, url: &str, asset: &Asset, tx: mpsc::Sender<PriceInfo>)
+        -> Self
+    {
+        Self {
+            url: schema.url(url, asset),
+            schema,
+            tx,
+            base: asset.symbol,
+            request_period: 1000,
+            rounding: Rounding::default(),
+            bucket: None,
+            clock: Arc::new(SystemClock),
+            skew: Arc::new(ClockSkew::default()),
+            retry: RetryPolicy::default(),
+            client: HttpConfig::default().build().unwrap_or_default(),
+            cadence: None,
+        }
+    }
+
+
+    /// Set request period in milliseconds. You should use 500 or more not to
+    /// overwhelm endpoint API.
+    ///
+    /// This describes how long should be the pause between requests to data
+    /// endpoint.
+    pub fn request_period_millis_set(&mut self, request_period: u64) {
+        self.request_period = request_period;
+    }
+
+
+
+    /// Set rounding mode for rates that have more decimal places than can be
+    /// stored.
+    pub fn rounding_set(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+
+
+    /// Set local rate limiting policy, it is applied even if endpoint does
+    /// not return any rate limiting information.
+    pub fn bucket_set(&mut self, bucket: Arc<TokenBucket>) {
+        self.bucket = Some(bucket);
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+
+
+    /// Set server clock offset estimate, collectors that request the same
+    /// server should share it.
+    pub fn skew_set(&mut self, skew: Arc<ClockSkew>) {
+        self.skew = skew;
+    }
+
+
+
+    /// Set backoff and circuit breaker settings for failed requests.
+    pub fn retry_set(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+
+
+    /// Set HTTP client, by default client with default HttpConfig is used.
+    pub fn client_set(&mut self, client: Client) {
+        self.client = client;
+    }
+
+
+
+    /// Enable adaptive polling, see cadence.rs. It has no effect if endpoint
+    /// does not return timestamps, then fixed request period is used.
+    pub fn cadence_set(&mut self, cadence: CadencePolicy) {
+        self.cadence = Some(cadence);
+    }
+}
+
+
+
+#[async_trait]
+impl<S: Schema> Source for AsyncHTTPCollector<S> {
+    async fn main(self, shared_state: Arc<SharedState>) {
+        main(self, shared_state).await
+    }
+}
+
+
+
+async fn main<S: Schema>(collector: AsyncHTTPCollector<S>,
+    shared_state: Arc<SharedState>
+)
+{
+    // Desired/targeted request period
+    let req_period = Duration::from_millis(collector.request_period);
+
+    let clock = collector.clock.clone();
+
+    let mut rate_limit = RateLimit::default();
+    rate_limit.clock_set(clock.clone());
+    rate_limit.skew_set(collector.skew.clone());
+    if let Some(ref bucket) = collector.bucket {
+        rate_limit.bucket_set(bucket.clone());
+    }
+
+    let mut retry = Retry::new(collector.retry);
+
+    let mut validators = Validators::default();
+    let mut timestamp_last = None;
+
+    // Adaptive period needs timestamps of upstream updates.
+    let mut cadence = collector.cadence
+        .filter(|_| collector.schema.upstream_timestamp())
+        .map(Cadence::new);
+
+    let not_modified = shared_state.metrics.get(
+        &format!("http_not_modified_total{{url=\"{}\"}}", collector.url)
+    );
+    let duplicates = shared_state.metrics.get(
+        &format!("http_duplicates_total{{url=\"{}\"}}", collector.url)
+    );
+
+    // Relaxed load, because we do not care on nanosecond shut down
+    // precission. We just have to shut down at some point.
+    while shared_state.shut_down.load(Ordering::Relaxed) == 0 {
+        retry.attempt();
+        rate_limit.start();
+
+        // We consider that the request is made at this point in time, although it
+        // is not completeley true:
+        // 1. DNS resolution takes time, thus real connection to server happens at
+        //    a later time. We could resolve DNS beforehand, cache it's IP address
+        //    and reuse that in consecutive requests. But let's assume we have a
+        //    niceley set up local DNS resolver that does caching for us.
+        // 2. Request building takes time. For some use-cases we could clone
+        //    RequestBuilder instead of creating new per each request. In this case
+        //    we do not do that because overhead is comparativeley neglible.
+        let start = clock.now();
+        let started = clock.instant();
+
+        let result = poll(&collector, &mut rate_limit, &mut validators, &start)
+            .await;
+
+        // Minimal delay of the next request after failure.
+        let mut delay = Duration::ZERO;
+        let mut limited = false;
+
+        match result {
+            // At the moment this is a conscious decission to lose data if
+            // our backend can not keep up with incomming data. Because
+            // there is no point to buffer too much old data when what we
+            // need is real time data.
+            Ok(info) => {
+                if retry.failures() > 0 {
+                    eprintln!("WARNING: {} recovered after {} failures",
+                        collector.url, retry.failures()
+                    );
+                }
+                retry.success();
+
+                match info {
+                    None => {
+                        not_modified.fetch_add(1, Ordering::Relaxed);
+                        cadence.iter_mut().for_each(Cadence::miss);
+                    }
+
+                    Some(info) if collector.schema.upstream_timestamp()
+                        && timestamp_last.is_some_and(|ts| info.timestamp <= ts)
+                        => {
+                        duplicates.fetch_add(1, Ordering::Relaxed);
+                        cadence.iter_mut().for_each(Cadence::miss);
+                    }
+
+                    Some(info) => {
+                        timestamp_last = Some(info.timestamp);
+
+                        if let Some(ref mut cadence) = cadence {
+                            cadence.update(UNIX_EPOCH
+                                + Duration::from_secs(info.timestamp)
+                            );
+                        }
+
+                        if collector.tx.try_send(info).is_err() {
+                            eprintln!(concat!("ERROR: backend can not process",
+                                " incomming data fast enough, dropping packet."
+                            ));
+                        }
+                    }
+                }
+            }
+
+            // Rate limit exceeded is not a failure of endpoint, next request
+            // is delayed by rate limit itself.
+            Err(CollectError::Status(StatusCode::TOO_MANY_REQUESTS)) => {
+                limited = true;
+            }
+
+            Err(e) => {
+                delay = req_period.max(retry.failure(e.class()));
+
+                if retry.state() == BreakerState::Open {
+                    eprintln!(concat!("ERROR: request to {} failed {} times in",
+                        " a row, last error: {}, suspending requests for {} s"
+                    ), collector.url, retry.failures(), e, delay.as_secs());
+                }
+                else {
+                    eprintln!(concat!("ERROR: request to {} failed: {},",
+                        " retrying in {} ms"
+                    ), collector.url, e, delay.as_millis());
+                }
+            }
+        }
+
+        let mut ts_next_req = match cadence {
+            Some(ref mut cadence) => cadence.next(start, clock.now()),
+            None => start + req_period,
+        };
+        ts_next_req = ts_next_req.max(start + delay);
+        rate_limit.ts_next_req_adjust(&mut ts_next_req);
+
+        // Rate limits are in wall time, but we wait in monotonic time counted
+        // from request start, so that wall clock adjustments do not matter.
+        let deadline = clock::deadline(ts_next_req, start, started);
+
+        if limited {
+            let delay = deadline.saturating_duration_since(clock.instant());
+
+            eprintln!(concat!("WARNING: remote endpoint rate limit exceeded,",
+                " next request in {} ms"
+            ), delay.as_millis());
+        }
+
+        // If current process is capable to handle responses fast enough it should
+        // have some sleep duration available. If not, then we do not sleep at all
+        // and employ best effort processing. Sleep is split into short steps,
+        // so that shut down is not delayed by long backoff.
+        while clock.instant() < deadline {
+            if shared_state.shut_down.load(Ordering::Relaxed) != 0 {
+                return
+            }
+
+            clock.sleep_until(deadline.min(clock.instant() + CHECK_PERIOD)).await;
+        }
+    }
+}
+
+
+
+// Make single request and decode response, None means that data has not
+// been modified since the last accepted response.
+async fn poll<S: Schema>(collector: &AsyncHTTPCollector<S>,
+    rate_limit: &mut RateLimit, validators: &mut Validators, start: &SystemTime
+)
+    -> Result<Option<PriceInfo>, CollectError>
+{
+    let req = validators.apply(collector.client.get(&collector.url));
+    let response = req.send().await?;
+
+    rate_limit.update_from_response(start, &response);
+
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(None)
+    }
+    else if status != StatusCode::OK {
+        return Err(CollectError::Status(status))
+    }
+
+    let headers = response.headers().clone();
+    let body = response.text().await?;
+
+    let info = collector.schema.decode(&body, collector.base,
+        collector.rounding, &collector.skew, collector.clock.as_ref()
+    )?;
+
+    let invalid = match info.rate {
+        _ if info.timestamp == 0 => Some("timestamp is missing"),
+        Some(rate) if rate.value() > 0 => None,
+        Some(_) => Some("rate is zero"),
+        None => Some("rate is missing"),
+    };
+
+    if let Some(e) = invalid {
+        return Err(CollectError::Validation(e.to_string()))
+    }
+
+    // Validators of rejected response are not kept, so that the same data is
+    // requested and checked again.
+    validators.update(&headers);
+
+    Ok(Some(info))
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use rate_limit::clock::MockClock;
+    use crate::source::{
+        coincap::CoinCap,
+        retry::Backoff,
+    };
+
+    fn body(timestamp: u64) -> String {
+        format!(concat!(r#"{{"data":{{"symbol":"btc","rateUsd":"67123.45"}},"#,
+            r#""timestamp":{}}}"#
+        ), timestamp)
+    }
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    fn ok(body: &str) -> Option<String> {
+        Some(format!(concat!("HTTP/1.1 200 OK\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n{}"
+        ), body.len(), body))
+    }
+
+    fn empty(status: &str) -> Option<String> {
+        Some(format!(concat!("{}\r\n",
+            "Content-Length: 0\r\n",
+            "Connection: close\r\n\r\n"
+        ), status))
+    }
+
+    // Stand-in endpoint that answers each connection with next of given
+    // responses, None closes connection without response. Returns endpoint
+    // URL and times, by mock clock, when requests arrived together with
+    // lowercased requests.
+    async fn serve(clock: MockClock, responses: Vec<Option<String>>)
+        -> (String, JoinHandle<Vec<(SystemTime, String)>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v2/rates", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let mut arrivals = Vec::new();
+
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(len > 0);
+                arrivals.push((clock.now(),
+                    String::from_utf8_lossy(&buf[..len]).to_lowercase()
+                ));
+
+                if let Some(response) = response {
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+
+            arrivals
+        });
+
+        (url, server)
+    }
+
+    // Run collector configured by `setup` until it sends `count` rates, then
+    // shut it down.
+    async fn collect(clock: MockClock, url: &str,
+        setup: impl FnOnce(&mut AsyncHTTPCollector<CoinCap>), count: usize
+    )
+        -> Arc<SharedState>
+    {
+        let asset = Asset { id: "bitcoin".to_string(), symbol: None };
+        let (tx, mut rx) = mpsc::channel(10);
+
+        let mut collector = AsyncHTTPCollector::new(CoinCap, url, &asset, tx);
+        collector.request_period_millis_set(800);
+        collector.clock_set(Arc::new(clock));
+        setup(&mut collector);
+        // Client timeouts would let paused clock jump ahead while collector
+        // waits for real I/O.
+        collector.client_set(Client::new());
+
+        let state = Arc::new(SharedState::default());
+        let collector_h = tokio::spawn(collector.main(state.clone()));
+
+        for _ in 0..count {
+            let info = rx.recv().await.unwrap();
+            assert_eq!(info.rate.unwrap().to_string(), "67123.45");
+        }
+
+        state.shut_down.store(1, Ordering::Relaxed);
+        collector_h.await.unwrap();
+
+        state
+    }
+
+    async fn arrivals(server: JoinHandle<Vec<(SystemTime, String)>>)
+        -> Vec<SystemTime>
+    {
+        server.await.unwrap().into_iter().map(|(ts, _)| ts).collect()
+    }
+
+    /// Requests are paced by request period and Retry-After, time is driven
+    /// by paused tokio clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_schedule() {
+        let clock = MockClock::new(at(1_700_000_000_000));
+
+        let (url, server) = serve(clock, vec![
+            empty("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3"),
+            ok(&body(1_717_171_717_123)),
+            ok(&body(1_717_171_718_123)),
+        ]).await;
+
+        collect(clock, &url, |_| {}, 2).await;
+
+        assert_eq!(arrivals(server).await, vec![at(1_700_000_000_000),
+            at(1_700_000_003_000), at(1_700_000_003_800)
+        ]);
+    }
+
+    /// Collector survives failures, backs off and suspends requests once
+    /// breaker opens, then recovers.
+    #[tokio::test(start_paused = true)]
+    async fn test_retry() {
+        let clock = MockClock::new(at(1_700_000_000_000));
+
+        let (url, server) = serve(clock, vec![
+            None,
+            empty("HTTP/1.1 503 Service Unavailable"),
+            ok("{}"),
+            ok(&body(1_717_171_717_123)),
+            ok(&body(1_717_171_718_123)),
+        ]).await;
+
+        let secs = Duration::from_secs;
+        collect(clock, &url, |collector| collector.retry_set(RetryPolicy {
+            transient: Backoff { min: secs(1), max: secs(30) },
+            permanent: Backoff { min: secs(10), max: secs(300) },
+            failures_max: 3,
+            cooldown: secs(60),
+        }), 2).await;
+
+        // Connection reset and 503 are retried after 1 s and 2 s, decode
+        // error is third failure in a row, so breaker opens for 60 s.
+        assert_eq!(arrivals(server).await, vec![at(1_700_000_000_000),
+            at(1_700_000_001_000), at(1_700_000_003_000),
+            at(1_700_000_063_000), at(1_700_000_063_800)
+        ]);
+    }
+
+    /// Validators are sent back, 304 and rates that are not newer than the
+    /// last forwarded one, even if rate has changed within the same second,
+    /// are not forwarded, but are counted.
+    #[tokio::test(start_paused = true)]
+    async fn test_conditional() {
+        let clock = MockClock::new(at(1_700_000_000_000));
+
+        // The same rate is returned with different ETag.
+        let same = body(1_717_171_717_123);
+        let validated = |etag: &str| Some(format!(concat!("HTTP/1.1 200 OK\r\n",
+            "ETag: {}\r\n",
+            "Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n{}"
+        ), etag, same.len(), same));
+
+        let (url, server) = serve(clock, vec![
+            validated("\"v1\""),
+            empty("HTTP/1.1 304 Not Modified"),
+            validated("\"v2\""),
+            ok(&body(1_717_171_717_823).replace("67123.45", "67124")),
+            ok(&body(1_717_171_718_123)),
+        ]).await;
+
+        let state = collect(clock, &url, |_| {}, 2).await;
+
+        let requests: Vec<String> = server.await.unwrap().into_iter()
+            .map(|(_, req)| req)
+            .collect();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\"\r\n"));
+        assert!(requests[1].contains(
+            "if-modified-since: sun, 06 nov 1994 08:49:37 gmt\r\n"
+        ));
+        assert!(requests[3].contains("if-none-match: \"v2\"\r\n"));
+
+        let metric = |name: &str| {
+            state.metrics.get(&format!("{}{{url=\"{}/bitcoin\"}}", name, url))
+                .load(Ordering::Relaxed)
+        };
+        assert_eq!(metric("http_not_modified_total"), 1);
+        assert_eq!(metric("http_duplicates_total"), 2);
+    }
+
+    /// Adaptive polling searches for the next update with growing delay, then
+    /// polls shortly after expected updates.
+    #[tokio::test(start_paused = true)]
+    async fn test_cadence() {
+        let clock = MockClock::new(at(1_700_000_000_000));
+
+        // Upstream updates every 5 s.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v2/rates", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut arrivals = Vec::new();
+
+            for _ in 0..7 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0u8; 4096];
+                assert!(stream.read(&mut buf).await.unwrap() > 0);
+
+                let now = clock.now();
+                arrivals.push(now);
+
+                let millis = now.duration_since(UNIX_EPOCH).unwrap().as_millis();
+                let timestamp = millis as u64 / 5000 * 5000;
+                let response = ok(&body(timestamp)).unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+
+            arrivals
+        });
+
+        collect(clock, &url, |collector| collector.cadence_set(CadencePolicy {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            margin: Duration::from_millis(200),
+        }), 4).await;
+
+        assert_eq!(server.await.unwrap(), vec![at(1_700_000_000_000),
+            at(1_700_000_001_000), at(1_700_000_002_000), at(1_700_000_004_000),
+            at(1_700_000_008_000), at(1_700_000_010_200), at(1_700_000_015_200)
+        ]);
+    }
+
+    #[test]
+    fn test_error_class() {
+        let class = |status| CollectError::Status(status).class();
+        assert_eq!(class(StatusCode::BAD_GATEWAY), ErrorClass::Transient);
+        assert_eq!(class(StatusCode::REQUEST_TIMEOUT), ErrorClass::Transient);
+        assert_eq!(class(StatusCode::NOT_FOUND), ErrorClass::Permanent);
+        assert_eq!(CollectError::Validation("rate is zero".to_string()).class(),
+            ErrorClass::Permanent
+        );
+    }
+}
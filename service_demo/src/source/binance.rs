@@ -0,0 +1,286 @@
+//! Binance `/api/v3/ticker/price` REST endpoint and `trade` WebSocket stream
+//! schemas.
+//!
+//! REST endpoint returns latest price of single trading pair, i.e.
+//! `{"symbol":"BTCUSDT","price":"67123.45000000"}`, stream pushes every trade
+//! of subscribed pairs, i.e.
+//! `{"e":"trade","s":"BTCUSDT","p":"67123.45000000","q":"0.1",...}`, trade
+//! quantity is forwarded as volume.
+//! Pair is returned as one concatenated code, so asset symbol must be
+//! configured to know where base ends and quote starts, i.e.
+//! `ASSETS=btcusdt:BTC`.
+
+
+
+use serde::Deserialize;
+
+use rate_limit::{
+    clock::Clock,
+    skew::ClockSkew,
+};
+
+use crate::{
+    price_info::PriceInfo,
+    decimal::{
+        price_parse,
+        volume_parse,
+        Rounding,
+    },
+    ohlc::Ohlc,
+    source::{
+        timestamp_now,
+        async_http_collector::{
+            DecodeError,
+            Schema,
+        },
+        websocket_collector::StreamSchema,
+    },
+    symbol::{
+        Asset,
+        Symbol,
+    },
+};
+
+
+
+/// Binance ticker price endpoint or trade stream.
+///
+/// For REST endpoint asset id is passed as `symbol` query parameter, i.e.
+/// https://api.binance.com/api/v3/ticker/price?symbol=BTCUSDT. For stream
+/// all assets are subscribed over single connection to configured URL, i.e.
+/// wss://stream.binance.com:9443/ws.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binance;
+
+
+
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedTicker {
+    symbol: String,
+    price: String,
+}
+
+
+
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedTrade {
+    #[serde(rename(deserialize = "s"))]
+    symbol: String,
+
+    #[serde(rename(deserialize = "p"))]
+    price: String,
+
+    #[serde(rename(deserialize = "q"))]
+    quantity: Option<String>,
+
+    /// Trade time in milliseconds.
+    #[serde(rename(deserialize = "T"))]
+    time: u64,
+}
+
+
+
+/// Messages pushed by stream, besides trades there are responses to
+/// subscribe requests, i.e. `{"result":null,"id":1}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum DecodedMessage {
+    Trade(DecodedTrade),
+    Response {
+        #[allow(dead_code)]
+        id: u64,
+    },
+}
+
+
+
+/// Split concatenated pair code, i.e. BTCUSDT, into base and quote.
+fn pair_split(code: &str, base: Option<Symbol>)
+    -> Result<(Symbol, Symbol), DecodeError>
+{
+    let quote = base.and_then(|base| {
+        code.to_ascii_uppercase().strip_prefix(base.as_str())
+            .map(str::to_string)
+    });
+
+    let (Some(base), Some(quote)) = (base, quote) else {
+        return Err(DecodeError::Pair(code.to_string()))
+    };
+
+    Ok((base, Symbol::new(&quote)?))
+}
+
+
+
+impl Schema for Binance {
+    fn url(&self, url: &str, asset: &Asset) -> String {
+        format!("{}?symbol={}", url, asset.id.to_ascii_uppercase())
+    }
+
+
+
+    fn decode(&self, body: &str, base: Option<Symbol>, rounding: Rounding,
+        _skew: &ClockSkew, clock: &dyn Clock
+    )
+        -> Result<PriceInfo, DecodeError>
+    {
+        let decoded: DecodedTicker = serde_json::from_str(body)?;
+        let (base, quote) = pair_split(&decoded.symbol, base)?;
+        let rate = price_parse(&decoded.price, rounding)?;
+
+        // Endpoint does not return timestamp, so local time of response is
+        // used instead.
+        Ok(PriceInfo::new(timestamp_now(clock), base, quote, Some(rate)))
+    }
+
+
+
+    fn upstream_timestamp(&self) -> bool {
+        false
+    }
+}
+
+
+
+impl StreamSchema for Binance {
+    fn url(&self, url: &str, _assets: &[Asset]) -> String {
+        url.to_string()
+    }
+
+
+
+    fn subscribe(&self, assets: &[Asset]) -> Vec<String> {
+        let params: Vec<String> = assets.iter()
+            .map(|a| format!("{}@trade", a.id.to_ascii_lowercase()))
+            .collect();
+
+        let msg = serde_json::json!({
+            "method": "SUBSCRIBE",
+            "params": params,
+            "id": 1,
+        });
+
+        vec![msg.to_string()]
+    }
+
+
+
+    fn decode(&self, msg: &str, assets: &[Asset], rounding: Rounding,
+        _clock: &dyn Clock
+    )
+        -> Result<Vec<PriceInfo>, DecodeError>
+    {
+        let trade = match serde_json::from_str(msg)? {
+            DecodedMessage::Trade(trade) => trade,
+            DecodedMessage::Response { .. } => return Ok(Vec::new()),
+        };
+
+        let Some(asset) = assets.iter()
+            .find(|a| a.id.eq_ignore_ascii_case(&trade.symbol))
+        else {
+            return Err(DecodeError::Pair(trade.symbol))
+        };
+
+        let (base, quote) = pair_split(&trade.symbol, asset.symbol)?;
+        let rate = price_parse(&trade.price, rounding)?;
+
+        let mut info = PriceInfo::new(trade.time / 1000, base, quote, Some(rate));
+        info.volume = trade.quantity
+            .map(|quantity| volume_parse(&quantity, Ohlc::VOLUME_SCALE))
+            .transpose()?;
+
+        Ok(vec![info])
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{
+        Duration,
+        UNIX_EPOCH,
+    };
+    use rate_limit::clock::MockClock;
+
+    /// Endpoint does not return timestamp, so it is taken from clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_decode() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1717171717));
+        let btc = Symbol::new("BTC").unwrap();
+        let body = r#"{"symbol":"BTCUSDT","price":"67123.45000000"}"#;
+
+        let info = Schema::decode(&Binance, body, Some(btc), Rounding::Down,
+            &ClockSkew::default(), &clock
+        ).unwrap();
+        assert_eq!(info.base, btc);
+        assert_eq!(info.quote.as_str(), "USDT");
+        assert_eq!(info.rate.unwrap().to_string(), "67123.45");
+        assert_eq!(info.timestamp, 1717171717);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let info = Schema::decode(&Binance, body, Some(btc), Rounding::Down,
+            &ClockSkew::default(), &clock
+        ).unwrap();
+        assert_eq!(info.timestamp, 1717171722);
+
+        let eth = Symbol::new("ETH").unwrap();
+        let decode = |body: &str, base| {
+            Schema::decode(&Binance, body, base, Rounding::Down,
+                &ClockSkew::default(), &clock
+            )
+        };
+
+        assert!(matches!(decode(body, Some(eth)), Err(DecodeError::Pair(..))));
+        assert!(matches!(decode(body, None), Err(DecodeError::Pair(..))));
+        assert!(matches!(decode(r#"{"symbol":"BTC","price":"1"}"#, Some(btc)),
+            Err(DecodeError::Symbol(..))
+        ));
+    }
+
+    #[test]
+    fn test_stream_decode() {
+        let btc = Symbol::new("BTC").unwrap();
+        let assets = [Asset { id: "btcusdt".to_string(), symbol: Some(btc) }];
+
+        assert_eq!(Binance.subscribe(&assets),
+            vec![r#"{"id":1,"method":"SUBSCRIBE","params":["btcusdt@trade"]}"#]
+        );
+
+        let clock = MockClock::new(UNIX_EPOCH);
+        let decode = |msg: &str| {
+            StreamSchema::decode(&Binance, msg, &assets, Rounding::Down, &clock)
+        };
+
+        let infos = decode(concat!(r#"{"e":"trade","s":"BTCUSDT","#,
+            r#""p":"67123.45000000","q":"0.1","T":1717171717123}"#
+        )).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].base, infos[0].quote.as_str(), infos[0].timestamp),
+            (btc, "USDT", 1717171717)
+        );
+        assert_eq!(infos[0].rate.unwrap().to_string(), "67123.45");
+        assert_eq!(infos[0].volume.unwrap().to_string(), "0.10000000");
+
+        // Quantity is rounded half up to volume scale, not by rate rounding.
+        let infos = decode(concat!(r#"{"e":"trade","s":"BTCUSDT","#,
+            r#""p":"1","q":"0.123456789","T":1}"#
+        )).unwrap();
+        assert_eq!(infos[0].volume.unwrap().to_string(), "0.12345679");
+
+        assert!(decode(r#"{"result":null,"id":1}"#).unwrap().is_empty());
+        assert!(matches!(decode(r#"{"e":"trade","s":"ETHUSDT","p":"1","T":1}"#),
+            Err(DecodeError::Pair(..))
+        ));
+    }
+
+    #[test]
+    fn test_url() {
+        let asset = Asset { id: "btcusdt".to_string(), symbol: None };
+
+        assert_eq!(Schema::url(&Binance, "https://api.binance.com/api/v3/ticker/price", &asset),
+            "https://api.binance.com/api/v3/ticker/price?symbol=BTCUSDT"
+        );
+    }
+}
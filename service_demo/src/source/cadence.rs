@@ -0,0 +1,275 @@
+//! Adaptive polling period, learned from upstream update cadence.
+//!
+//! Endpoint updates data at its own pace, polling more often wastes requests,
+//! polling less often delays data. Interval between upstream updates is
+//! learned from timestamps of returned data and next poll is aligned shortly
+//! after next expected update.
+//!
+//! Polls that happen to be aligned after update can only see intervals that
+//! are multiples of real interval, so the shortest recent interval is used
+//! and from time to time poll is made in the middle of expected interval to
+//! find out whether upstream has become faster. If expected update is not
+//! there yet, endpoint is polled again with growing delay starting from
+//! minimal period.
+
+
+
+use std::{
+    collections::VecDeque,
+    env,
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use rate_limit::config::env_parse_millis;
+
+
+
+/// Number of recent intervals that estimate is taken from.
+const SAMPLES: usize = 8;
+
+/// Every that many aligned polls one is made in the middle of interval.
+const PROBE_EVERY: u32 = 8;
+
+
+
+/// Adaptive polling configuration.
+///
+/// `min` - minimal period between polls, it is used until interval is
+/// learned.
+/// `max` - maximal period between polls.
+/// `margin` - poll is made that long after expected update, so that update
+/// is not missed because of clock offset or timestamp rounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CadencePolicy {
+    pub min: Duration,
+    pub max: Duration,
+    pub margin: Duration,
+}
+
+
+
+impl Default for CadencePolicy {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+            margin: Duration::from_millis(200),
+        }
+    }
+}
+
+
+
+impl CadencePolicy {
+    /// Load policy from ENV, returns None if `POLL_ADAPTIVE` is not `true`.
+    /// Parameters that are not set keep default values.
+    ///
+    /// `POLL_PERIOD_MIN_MILLIS` - minimal period.
+    /// `POLL_PERIOD_MAX_MILLIS` - maximal period.
+    /// `POLL_MARGIN_MILLIS` - delay after expected update.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let adaptive = env::var("POLL_ADAPTIVE").unwrap_or_default();
+        match adaptive.trim() {
+            "true" => {},
+            "" | "false" => return Ok(None),
+            _ => return Err(format!("POLL_ADAPTIVE is not valid: {}", adaptive)),
+        }
+
+        let mut policy = Self::default();
+
+        env_parse_millis("POLL_PERIOD_MIN_MILLIS", &mut policy.min)?;
+        env_parse_millis("POLL_PERIOD_MAX_MILLIS", &mut policy.max)?;
+        env_parse_millis("POLL_MARGIN_MILLIS", &mut policy.margin)?;
+
+        if policy.min.is_zero() || policy.min > policy.max {
+            return Err(concat!("POLL_PERIOD_MIN_MILLIS must be positive and not",
+                " greater than POLL_PERIOD_MAX_MILLIS"
+            ).to_string())
+        }
+
+        Ok(Some(policy))
+    }
+}
+
+
+
+/// Learned upstream update cadence of single endpoint.
+///
+/// `last` - timestamp of the last upstream update, by local clock.
+/// `samples` - recent intervals between updates.
+/// `misses` - consecutive polls that returned no new data.
+/// `aligned` - aligned polls since the last probe.
+#[derive(Debug, Clone)]
+pub struct Cadence {
+    policy: CadencePolicy,
+    last: Option<SystemTime>,
+    samples: VecDeque<Duration>,
+    misses: u32,
+    aligned: u32,
+}
+
+
+
+impl Cadence {
+    pub fn new(policy: CadencePolicy) -> Self {
+        Self {
+            policy,
+            last: None,
+            samples: VecDeque::with_capacity(SAMPLES),
+            misses: 0,
+            aligned: 0,
+        }
+    }
+
+
+
+    /// Estimated interval between upstream updates, None until at least two
+    /// updates were seen.
+    pub fn interval(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+
+
+    /// Poll returned data updated at `ts`.
+    pub fn update(&mut self, ts: SystemTime) {
+        match self.last {
+            Some(last) if ts <= last => return self.miss(),
+            Some(last) => {
+                if self.samples.len() == SAMPLES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(ts.duration_since(last).unwrap_or_default());
+            }
+            None => {}
+        }
+
+        self.last = Some(ts);
+        self.misses = 0;
+    }
+
+
+
+    /// Poll returned no new data.
+    pub fn miss(&mut self) {
+        self.misses = self.misses.saturating_add(1);
+    }
+
+
+
+    /// Time of the next poll, `start` is the time when current poll was
+    /// made, `now` is current time.
+    pub fn next(&mut self, start: SystemTime, now: SystemTime) -> SystemTime {
+        let policy = self.policy;
+
+        let ts = match (self.interval(), self.last) {
+            // Expected update is late, look for it with growing delay.
+            _ if self.misses > 0 => {
+                start + policy.min.saturating_mul(1 << (self.misses - 1).min(16))
+            }
+
+            (Some(interval), Some(last)) if !interval.is_zero() => {
+                self.aligned += 1;
+
+                let step = if self.aligned >= PROBE_EVERY {
+                    self.aligned = 0;
+                    interval / 2
+                }
+                else {
+                    interval
+                };
+
+                // The first expected update after now, updates that should
+                // have already been seen are skipped.
+                let elapsed = now.duration_since(last).unwrap_or_default()
+                    .saturating_sub(policy.margin);
+                let n = elapsed.as_nanos() / step.as_nanos() + 1;
+
+                last + step.saturating_mul(u32::try_from(n).unwrap_or(u32::MAX))
+                    + policy.margin
+            }
+
+            // Cadence is not learned yet.
+            _ => start + policy.min,
+        };
+
+        ts.min(start + policy.max).max(start + policy.min)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_cadence() {
+        let mut cadence = Cadence::new(CadencePolicy {
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+            margin: Duration::from_millis(200),
+        });
+
+        // Minimal period is used until interval is learned.
+        cadence.update(at(100_000));
+        assert_eq!(cadence.next(at(100_300), at(100_350)), at(100_800));
+        cadence.update(at(105_000));
+        assert_eq!(cadence.interval(), Some(Duration::from_secs(5)));
+
+        // Poll is aligned after next expected update.
+        assert_eq!(cadence.next(at(105_300), at(105_350)), at(110_200));
+
+        // Late update is searched for with growing delay.
+        cadence.miss();
+        assert_eq!(cadence.next(at(110_200), at(110_250)), at(110_700));
+        cadence.update(at(105_000));
+        assert_eq!(cadence.next(at(110_700), at(110_750)), at(111_700));
+
+        // Interval that spans two updates does not change estimate.
+        cadence.update(at(111_000));
+        cadence.update(at(121_000));
+        assert_eq!(cadence.interval(), Some(Duration::from_secs(5)));
+
+        // Expected updates that have already passed are skipped.
+        assert_eq!(cadence.next(at(133_000), at(133_100)), at(136_200));
+
+        // Poll is not made sooner than minimal period.
+        assert_eq!(cadence.next(at(126_100), at(126_100)), at(126_600));
+    }
+
+    /// Shorter cadence is found by probe in the middle of interval.
+    #[test]
+    fn test_probe() {
+        let mut cadence = Cadence::new(CadencePolicy {
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            margin: Duration::ZERO,
+        });
+
+        let mut ts = 0;
+        cadence.update(at(ts));
+        for _ in 0..PROBE_EVERY - 2 {
+            ts += 4000;
+            cadence.update(at(ts));
+            cadence.next(at(ts), at(ts));
+        }
+
+        // Every 8th poll probes after half of interval.
+        ts += 4000;
+        cadence.update(at(ts));
+        assert_eq!(cadence.next(at(ts), at(ts)), at(ts + 4000));
+        assert_eq!(cadence.next(at(ts), at(ts)), at(ts + 2000));
+
+        cadence.update(at(ts + 2000));
+        assert_eq!(cadence.interval(), Some(Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,316 @@
+//! CoinCap `/v2/rates` REST endpoint and `prices` WebSocket stream schemas.
+//!
+//! REST endpoint returns single rate against USD per request, i.e.
+//! `{"data":{"symbol":"BTC","rateUsd":"67123.45"},"timestamp":1717171717123}`,
+//! stream pushes changed prices of subscribed assets by asset id, i.e.
+//! `{"bitcoin":"67123.45","ethereum":"3512.1"}`.
+
+
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    sync::{
+        Mutex,
+        OnceLock,
+    },
+};
+
+use std::time::{
+    Duration,
+    UNIX_EPOCH,
+};
+
+use serde::Deserialize;
+
+use rate_limit::{
+    clock::Clock,
+    skew::ClockSkew,
+};
+
+use crate::{
+    price_info::PriceInfo,
+    decimal::{
+        price_parse,
+        Rounding,
+    },
+    source::{
+        timestamp_now,
+        async_http_collector::{
+            DecodeError,
+            Schema,
+        },
+        websocket_collector::StreamSchema,
+    },
+    symbol::{
+        Asset,
+        Symbol,
+    },
+};
+
+
+
+/// CoinCap rates endpoint or prices stream.
+///
+/// For REST endpoint asset id is appended to configured URL, i.e.
+/// https://api.coincap.io/v2/rates/bitcoin. For stream asset ids are passed
+/// as `assets` query parameter, i.e.
+/// wss://ws.coincap.io/prices?assets=bitcoin,ethereum.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoinCap;
+
+
+
+/// Structure that stores deserialized response from crypto rates endpoint.
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedTicker {
+    symbol: String,
+
+    #[serde(rename(deserialize = "rateUsd"))]
+    rate_usd: String,
+}
+
+
+
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedBody {
+    data: DecodedTicker,
+    timestamp: u64,
+}
+
+
+
+impl DecodedBody {
+    /// Convert DecodedBody into PriceInfo for given pair.
+    ///
+    /// Caller decides which pair response belongs to, because rates endpoint
+    /// returns only base symbol and quote is implied. Rate keeps as many
+    /// decimal places as fit into Price, extra digits are rounded. Timestamp
+    /// is read by server clock, it is converted to local clock.
+    fn into_price_info(self, base: Symbol, quote: Symbol, rounding: Rounding,
+        skew: &ClockSkew
+    )
+        -> Result<PriceInfo, DecodeError>
+    {
+        let rate = price_parse(&self.data.rate_usd, rounding)?;
+
+        let ts = UNIX_EPOCH + Duration::from_millis(self.timestamp);
+        let ts = skew.to_local(ts);
+
+        // We round down to seconds resolution.
+        let timestamp = ts.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Ok(PriceInfo::new(timestamp, base, quote, Some(rate)))
+    }
+}
+
+
+
+impl Schema for CoinCap {
+    fn url(&self, url: &str, asset: &Asset) -> String {
+        format!("{}/{}", url.trim_end_matches('/'), asset.id)
+    }
+
+
+
+    fn decode(&self, body: &str, base: Option<Symbol>, rounding: Rounding,
+        skew: &ClockSkew, _clock: &dyn Clock
+    )
+        -> Result<PriceInfo, DecodeError>
+    {
+        let decoded: DecodedBody = serde_json::from_str(body)?;
+
+        let base = match base {
+            Some(base) => base,
+            None => Symbol::new(&decoded.data.symbol)?,
+        };
+
+        decoded.into_price_info(base, Symbol::USD, rounding, skew)
+    }
+}
+
+
+
+// Whether asset id is seen for the first time without configured symbol, so
+// that its rates are reported as skipped only once, not in every message.
+fn symbol_missing_first(id: &str) -> bool {
+    static MISSING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+    // Poisoned lock means that some thread has panicked while inserting into
+    // set, set itself is still valid.
+    let mut missing = match MISSING.get_or_init(Default::default).lock() {
+        Ok(guard) => guard,
+        Err(e) => e.into_inner(),
+    };
+
+    missing.insert(id.to_string())
+}
+
+
+
+impl StreamSchema for CoinCap {
+    fn url(&self, url: &str, assets: &[Asset]) -> String {
+        let ids: Vec<&str> = assets.iter().map(|a| a.id.as_str()).collect();
+
+        format!("{}?assets={}", url, ids.join(","))
+    }
+
+
+
+    // Assets are selected by URL, no subscribe messages are needed.
+    fn subscribe(&self, _assets: &[Asset]) -> Vec<String> {
+        Vec::new()
+    }
+
+
+
+    fn decode(&self, msg: &str, assets: &[Asset], rounding: Rounding,
+        clock: &dyn Clock
+    )
+        -> Result<Vec<PriceInfo>, DecodeError>
+    {
+        let decoded: HashMap<String, String> = serde_json::from_str(msg)?;
+
+        // Stream does not return timestamp, so local time of message is used
+        // instead.
+        let timestamp = timestamp_now(clock);
+
+        let mut ret = Vec::with_capacity(decoded.len());
+        for (id, rate) in decoded {
+            // Stream does not return symbols, so they must be configured.
+            // Other assets are skipped, so that single unknown asset does not
+            // drop rates of the rest.
+            let Some(base) = assets.iter()
+                .find(|a| a.id == id)
+                .and_then(|a| a.symbol)
+            else {
+                if symbol_missing_first(&id) {
+                    eprintln!(concat!("WARNING: symbol of asset {} is not",
+                        " configured, skipping its rates"
+                    ), id);
+                }
+                continue;
+            };
+
+            let rate = price_parse(&rate, rounding)?;
+            ret.push(PriceInfo::new(timestamp, base, Symbol::USD, Some(rate)));
+        }
+
+        Ok(ret)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rate_limit::clock::MockClock;
+    use crate::decimal::DecimalError;
+
+    fn body(rate: &str) -> String {
+        format!(r#"{{"data":{{"symbol":"btc","rateUsd":"{}"}},"timestamp":1717171717123}}"#,
+            rate
+        )
+    }
+
+    #[test]
+    fn test_decode() {
+        let rate = |s: &str| {
+            Schema::decode(&CoinCap, &body(s), None, Rounding::HalfUp,
+                &ClockSkew::default(), &MockClock::new(UNIX_EPOCH)
+            )
+                .map(|info| info.rate.unwrap().to_string())
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(rate("67123.4512345678901234"),
+            Ok("67123.45123456789012".to_string())
+        );
+        assert_eq!(rate("67123.45"), Ok("67123.45".to_string()));
+        assert_eq!(rate("67123"), Ok("67123".to_string()));
+        assert_eq!(rate("6.7123E4"), Ok("67123".to_string()));
+        let decode = |body: &str, base| {
+            Schema::decode(&CoinCap, body, base, Rounding::Down,
+                &ClockSkew::default(), &MockClock::new(UNIX_EPOCH)
+            )
+        };
+
+        assert!(matches!(decode(&body("abc"), None),
+            Err(DecodeError::Rate(DecimalError::InvalidChar(0)))
+        ));
+        assert!(matches!(decode("{}", None), Err(DecodeError::Json(..))));
+
+        let eth = Symbol::new("ETH").unwrap();
+        let info = decode(&body("1"), None).unwrap();
+        assert_eq!((info.timestamp, info.base.as_str(), info.quote),
+            (1717171717, "BTC", Symbol::USD)
+        );
+        let info = decode(&body("1"), Some(eth)).unwrap();
+        assert_eq!(info.base, eth);
+
+        // Server clock is 2 s ahead.
+        let skew = ClockSkew::default();
+        let now = UNIX_EPOCH + Duration::from_secs(1717171717);
+        skew.sample(now, now, now + Duration::from_secs(2));
+
+        let info = Schema::decode(&CoinCap, &body("1"), None, Rounding::Down,
+            &skew, &MockClock::new(UNIX_EPOCH)
+        ).unwrap();
+        assert_eq!(info.timestamp, 1717171715);
+    }
+
+    /// Stream does not return timestamp, so it is taken from clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_decode() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1717171717));
+        let btc = Symbol::new("BTC").unwrap();
+        let assets = [
+            Asset { id: "bitcoin".to_string(), symbol: Some(btc) },
+            Asset { id: "ethereum".to_string(), symbol: None },
+            Asset { id: "tether".to_string(), symbol: None },
+        ];
+
+        let decode = |msg: &str| {
+            StreamSchema::decode(&CoinCap, msg, &assets, Rounding::Down, &clock)
+        };
+
+        let infos = decode(r#"{"bitcoin":"67123.45"}"#).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].base, infos[0].quote), (btc, Symbol::USD));
+        assert_eq!(infos[0].rate.unwrap().to_string(), "67123.45");
+        assert_eq!(infos[0].timestamp, 1717171717);
+
+        tokio::time::advance(Duration::from_secs(90)).await;
+        let infos = decode(r#"{"bitcoin":"67123.45"}"#).unwrap();
+        assert_eq!(infos[0].timestamp, 1717171807);
+
+        // Symbols of ethereum and tether are not configured, so their rates
+        // are skipped, but rates of other assets are not.
+        let infos = decode(r#"{"ethereum":"3512.1","bitcoin":"67124"}"#).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].base, btc);
+        assert_eq!(decode(r#"{"tether":"1"}"#).unwrap().len(), 0);
+
+        // Missing symbol is reported once per asset.
+        assert!(symbol_missing_first("coincap-test"));
+        assert!(!symbol_missing_first("coincap-test"));
+
+        assert_eq!(StreamSchema::url(&CoinCap, "wss://ws.coincap.io/prices", &assets),
+            "wss://ws.coincap.io/prices?assets=bitcoin,ethereum,tether"
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        let asset = Asset { id: "bitcoin".to_string(), symbol: None };
+
+        assert_eq!(Schema::url(&CoinCap, "https://api.coincap.io/v2/rates/", &asset),
+            "https://api.coincap.io/v2/rates/bitcoin"
+        );
+    }
+}
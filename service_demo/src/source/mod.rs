@@ -0,0 +1,113 @@
+//! Data sources that produce PriceInfo.
+//!
+//! Every source sends PriceInfo into the same channel that is consumed by
+//! OhlcCalc, so calculations and storage do not depend on where rates come
+//! from. Source is selected by `SOURCE` configuration parameter.
+
+pub mod async_http_collector;
+pub mod cadence;
+pub mod retry;
+pub mod websocket_collector;
+pub mod coincap;
+pub mod binance;
+pub mod replay;
+pub mod simulated;
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+
+use rate_limit::clock::Clock;
+
+use crate::shared_state::SharedState;
+
+
+
+/// Source traits should implement this method, so that they can be run in
+/// separate async function.
+///
+/// Source should return when shut down is requested or when it can not
+/// produce any more data.
+#[async_trait]
+pub trait Source {
+    async fn main(self, shared_state: Arc<SharedState>);
+}
+
+
+
+/// Nice wrapper to abstract away Source trait.
+pub async fn main(source: impl Source, shared_state: Arc<SharedState>) {
+    source.main(shared_state).await
+}
+
+
+
+/// Sources that can be selected from configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceKind {
+    /// CoinCap `/v2/rates` REST endpoint polled over HTTP.
+    #[default]
+    CoinCap,
+    /// Binance `/api/v3/ticker/price` REST endpoint polled over HTTP.
+    Binance,
+    /// CoinCap `prices` WebSocket stream.
+    CoinCapWs,
+    /// Binance `trade` WebSocket stream.
+    BinanceWs,
+    /// Historical rates replayed from file or HTTP history endpoint.
+    Replay,
+    /// Synthetic rates, see simulated.rs.
+    Simulated,
+    /// Consensus of several sources, see consensus.rs.
+    Consensus,
+}
+
+
+
+impl FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "coincap" => Ok(Self::CoinCap),
+            "binance" => Ok(Self::Binance),
+            "coincap_ws" => Ok(Self::CoinCapWs),
+            "binance_ws" => Ok(Self::BinanceWs),
+            "replay" => Ok(Self::Replay),
+            "simulated" => Ok(Self::Simulated),
+            "consensus" => Ok(Self::Consensus),
+            _ => Err(format!("unknown source: {}", s)),
+        }
+    }
+}
+
+
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CoinCap => write!(f, "coincap"),
+            Self::Binance => write!(f, "binance"),
+            Self::CoinCapWs => write!(f, "coincap_ws"),
+            Self::BinanceWs => write!(f, "binance_ws"),
+            Self::Replay => write!(f, "replay"),
+            Self::Simulated => write!(f, "simulated"),
+            Self::Consensus => write!(f, "consensus"),
+        }
+    }
+}
+
+
+
+/// Current Unix timestamp in seconds by given clock, used for endpoints that
+/// do not return timestamp of the rate.
+pub(crate) fn timestamp_now(clock: &dyn Clock) -> u64 {
+    clock.now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,531 @@
+//! Source that replays historical rates as if they were live.
+//!
+//! Rates are loaded from a file or from HTTP history endpoint and are sent
+//! with their original timestamps, so OhlcCalc builds the same candles as it
+//! would have built if service had been running at that time. This is used to
+//! backfill gaps in stored data.
+//!
+//! Supported formats:
+//! - CSV with `timestamp,base,quote,rate` rows, timestamp in Unix seconds,
+//!   lines starting with `#` and header line are ignored.
+//! - CoinCap history JSON, i.e. `/v2/assets/bitcoin/history?interval=m1`
+//!   response `{"data":[{"priceUsd":"67123.45","time":1717171680000}]}`. It
+//!   contains rates of single asset against USD, so location must contain
+//!   `{id}` placeholder and asset symbols must be configured.
+
+
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    time::sleep,
+};
+
+use serde::Deserialize;
+
+use async_trait::async_trait;
+
+use reqwest::Client;
+
+use crate::{
+    shared_state::SharedState,
+    http_client::HttpConfig,
+    price_info::PriceInfo,
+    decimal::{
+        price_parse,
+        DecimalError,
+        Rounding,
+    },
+    source::Source,
+    symbol::{
+        Asset,
+        Symbol,
+        SymbolError,
+    },
+};
+
+
+
+/// Format of replayed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayFormat {
+    Csv,
+    /// CoinCap `/v2/assets/{id}/history` response.
+    CoinCapHistory,
+}
+
+
+
+impl FromStr for ReplayFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "coincap_history" => Ok(Self::CoinCapHistory),
+            _ => Err(format!("unknown replay format: {}", s)),
+        }
+    }
+}
+
+
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(String, std::io::Error),
+    Http(String, reqwest::Error),
+    Json(String, serde_json::Error),
+    /// Invalid CSV line, line number starts from 1.
+    Line(usize, String),
+    Symbol(SymbolError),
+    Rate(DecimalError),
+    /// History format contains single asset only, its symbol must be known.
+    AssetRequired(String),
+}
+
+
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(location, e) => write!(f, "could not read {}: {}", location, e),
+            Self::Http(location, e) => write!(f, "could not load {}: {}", location, e),
+            Self::Json(location, e) => {
+                write!(f, "could not decode {} as JSON: {}", location, e)
+            }
+            Self::Line(line, e) => write!(f, "invalid line {}: {}", line, e),
+            Self::Symbol(e) => write!(f, "invalid symbol: {}", e),
+            Self::Rate(e) => write!(f, "invalid rate: {}", e),
+            Self::AssetRequired(location) => {
+                write!(f, "symbol of the asset must be configured for {}", location)
+            }
+        }
+    }
+}
+
+
+
+impl From<SymbolError> for ReplayError {
+    fn from(e: SymbolError) -> Self {
+        Self::Symbol(e)
+    }
+}
+
+
+
+impl From<DecimalError> for ReplayError {
+    fn from(e: DecimalError) -> Self {
+        Self::Rate(e)
+    }
+}
+
+
+
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedHistoryItem {
+    #[serde(rename(deserialize = "priceUsd"))]
+    price_usd: String,
+
+    /// Timestamp in milliseconds.
+    time: u64,
+}
+
+
+
+#[derive(Deserialize, Debug, Clone)]
+struct DecodedHistory {
+    data: Vec<DecodedHistoryItem>,
+}
+
+
+
+/// Parse CSV with `timestamp,base,quote,rate` rows.
+pub fn csv_parse(text: &str, rounding: Rounding)
+    -> Result<Vec<PriceInfo>, ReplayError>
+{
+    let mut ret = Vec::new();
+
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [timestamp, base, quote, rate] = fields[..] else {
+            return Err(ReplayError::Line(num + 1,
+                "expected timestamp,base,quote,rate".to_string()
+            ))
+        };
+
+        let Ok(timestamp) = timestamp.parse::<u64>() else {
+            // Header line.
+            if num == 0 {
+                continue;
+            }
+
+            return Err(ReplayError::Line(num + 1,
+                format!("invalid timestamp: {}", timestamp)
+            ))
+        };
+
+        let rate = price_parse(rate, rounding)
+            .map_err(|e| ReplayError::Line(num + 1, e.to_string()))?;
+        let base = Symbol::new(base)
+            .map_err(|e| ReplayError::Line(num + 1, e.to_string()))?;
+        let quote = Symbol::new(quote)
+            .map_err(|e| ReplayError::Line(num + 1, e.to_string()))?;
+
+        ret.push(PriceInfo::new(timestamp, base, quote, Some(rate)));
+    }
+
+    Ok(ret)
+}
+
+
+
+/// Parse CoinCap history response of single asset.
+pub fn coincap_history_parse(text: &str, location: &str, base: Symbol,
+    rounding: Rounding
+)
+    -> Result<Vec<PriceInfo>, ReplayError>
+{
+    let decoded: DecodedHistory = serde_json::from_str(text)
+        .map_err(|e| ReplayError::Json(location.to_string(), e))?;
+
+    let mut ret = Vec::with_capacity(decoded.data.len());
+    for item in decoded.data {
+        let rate = price_parse(&item.price_usd, rounding)?;
+
+        ret.push(PriceInfo::new(item.time / 1000, base, Symbol::USD,
+            Some(rate)
+        ));
+    }
+
+    Ok(ret)
+}
+
+
+
+/// Source that replays historical rates.
+///
+/// `location` - file path or HTTP URL, if it contains `{id}`, data is loaded
+/// separately for every configured asset.
+/// `speed` - replay speed relative to original pace, i.e. 1 replays in real
+/// time, 60 replays hour in a minute. If it is 0, rates are sent as fast as
+/// they can be processed.
+/// `client` - HTTP client used for HTTP locations.
+pub struct Replay {
+    tx: mpsc::Sender<PriceInfo>,
+    location: String,
+    format: ReplayFormat,
+    assets: Vec<Asset>,
+    speed: u32,
+    rounding: Rounding,
+    client: Client,
+}
+
+
+
+impl Replay {
+    pub fn new(location: &str, format: ReplayFormat, assets: &[Asset],
+        tx: mpsc::Sender<PriceInfo>
+    )
+        -> Self
+    {
+        Self {
+            tx,
+            location: location.to_string(),
+            format,
+            assets: assets.to_vec(),
+            speed: 0,
+            rounding: Rounding::default(),
+            client: HttpConfig::default().build().unwrap_or_default(),
+        }
+    }
+
+
+
+    /// Set replay speed, see Replay.
+    pub fn speed_set(&mut self, speed: u32) {
+        self.speed = speed;
+    }
+
+
+
+    /// Set rounding mode for rates that have more decimal places than can be
+    /// stored.
+    pub fn rounding_set(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+
+
+    /// Set HTTP client, by default client with default HttpConfig is used.
+    pub fn client_set(&mut self, client: Client) {
+        self.client = client;
+    }
+
+
+
+    /// Load all rates, ordered by timestamp.
+    pub async fn load(&self) -> Result<Vec<PriceInfo>, ReplayError> {
+        let mut ret = Vec::new();
+
+        if self.location.contains("{id}") {
+            for asset in &self.assets {
+                let location = self.location.replace("{id}", &asset.id);
+                ret.extend(self.load_location(&location, Some(asset)).await?);
+            }
+        }
+        else {
+            ret = self.load_location(&self.location, None).await?;
+        }
+
+        // Stable sort keeps order of rates with equal timestamps.
+        ret.sort_by_key(|info| info.timestamp);
+
+        Ok(ret)
+    }
+
+
+
+    async fn load_location(&self, location: &str, asset: Option<&Asset>)
+        -> Result<Vec<PriceInfo>, ReplayError>
+    {
+        let text = if location.starts_with("http://")
+            || location.starts_with("https://")
+        {
+            let r = self.client.get(location).send().await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| ReplayError::Http(location.to_string(), e))?;
+
+            r.text().await
+                .map_err(|e| ReplayError::Http(location.to_string(), e))?
+        }
+        else {
+            tokio::fs::read_to_string(location).await
+                .map_err(|e| ReplayError::Io(location.to_string(), e))?
+        };
+
+        match self.format {
+            ReplayFormat::Csv => csv_parse(&text, self.rounding),
+            ReplayFormat::CoinCapHistory => {
+                let Some(base) = asset.and_then(|a| a.symbol) else {
+                    return Err(ReplayError::AssetRequired(location.to_string()))
+                };
+
+                coincap_history_parse(&text, location, base, self.rounding)
+            }
+        }
+    }
+}
+
+
+
+#[async_trait]
+impl Source for Replay {
+    async fn main(self, shared_state: Arc<SharedState>) {
+        let infos = match self.load().await {
+            Ok(infos) => infos,
+            Err(e) => {
+                eprintln!("ERROR: could not load replay data: {}", e);
+                return
+            }
+        };
+
+        let mut ts_prev: Option<u64> = None;
+
+        for info in infos {
+            if shared_state.shut_down.load(Ordering::Relaxed) != 0 {
+                return
+            }
+
+            if let (Some(ts_prev), true) = (ts_prev, self.speed > 0) {
+                let pause = info.timestamp.saturating_sub(ts_prev);
+                sleep(Duration::from_secs(pause) / self.speed).await;
+            }
+            ts_prev = Some(info.timestamp);
+
+            // Unlike live sources, replay waits for backend, because data is
+            // not getting old while it waits.
+            if self.tx.send(info).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::TcpListener,
+    };
+    use crate::{
+        ohlc::Ohlc,
+        ohlc_calc::{
+            self,
+            OhlcCalc,
+            OhlcMap,
+        },
+        atomic_swap::AtomicSwap,
+        source,
+    };
+
+    #[test]
+    fn test_csv_parse() {
+        let csv = concat!(
+            "timestamp,base,quote,rate\n",
+            "# comment\n",
+            "1717171680, btc, usd, 67123.45\n",
+            "\n",
+            "1717171740,ETH,USD,3512.1e0\n",
+        );
+
+        let infos = csv_parse(csv, Rounding::Down).unwrap();
+        assert_eq!(infos.len(), 2);
+        assert_eq!((infos[0].timestamp, infos[0].base.as_str(), infos[0].quote),
+            (1717171680, "BTC", Symbol::USD)
+        );
+        assert_eq!(infos[1].rate.unwrap().to_string(), "3512.1");
+
+        assert!(matches!(csv_parse("1,BTC,USD", Rounding::Down),
+            Err(ReplayError::Line(1, ..))
+        ));
+        assert!(matches!(csv_parse("1,BTC,USD,1\nx,BTC,USD,1", Rounding::Down),
+            Err(ReplayError::Line(2, ..))
+        ));
+        assert!(matches!(csv_parse("1,BTC,USD,-1", Rounding::Down),
+            Err(ReplayError::Line(1, ..))
+        ));
+    }
+
+    #[test]
+    fn test_coincap_history_parse() {
+        let btc = Symbol::new("BTC").unwrap();
+        let json = r#"{"data":[{"priceUsd":"67123.45","time":1717171680000}]}"#;
+
+        let infos = coincap_history_parse(json, "test", btc, Rounding::Down)
+            .unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].timestamp, infos[0].base), (1717171680, btc));
+
+        assert!(matches!(coincap_history_parse("[]", "test", btc, Rounding::Down),
+            Err(ReplayError::Json(..))
+        ));
+    }
+
+    /// Replayed rates are aggregated with their original timestamps, every
+    /// finished candle reaches storage and nothing is dropped.
+    #[tokio::test]
+    async fn test_replay_file_to_storage() {
+        let path = std::env::temp_dir()
+            .join(format!("replay_test_{}.csv", std::process::id()));
+
+        let mut csv = String::new();
+        for minute in 0..300u64 {
+            let ts = 1717171200 + minute * 60;
+            csv.push_str(&format!("{},BTC,USD,{}.5\n", ts + 10, 100 + minute));
+            csv.push_str(&format!("{},BTC,USD,{}\n", ts + 50, 99 + minute));
+        }
+        tokio::fs::write(&path, csv).await.unwrap();
+
+        // Storage channel is sized like in main, so it fills up before slow
+        // storage starts to read it.
+        let (tx, rx) = mpsc::channel(10);
+        let (tx_storage, mut rx_storage) = mpsc::channel::<Ohlc>(200);
+        let terminal = Arc::new(AtomicSwap::new(Box::new(Some(OhlcMap::new()))));
+
+        let replay = Replay::new(path.to_str().unwrap(), ReplayFormat::Csv, &[],
+            tx
+        );
+        let mut calc = OhlcCalc::new(rx, tx_storage, terminal);
+        calc.history_set(true);
+
+        let storage_h = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut ohlcs = Vec::new();
+            while let Some(ohlc) = rx_storage.recv().await {
+                ohlcs.push(ohlc);
+            }
+
+            ohlcs
+        });
+
+        let state = Arc::new(SharedState::default());
+        let calc_h = tokio::spawn(ohlc_calc::main(calc, state.clone()));
+        source::main(replay, state.clone()).await;
+        calc_h.await.unwrap();
+
+        let _ = std::fs::remove_file(&path);
+
+        // Last candle is finished once replay ends, nothing is dropped.
+        let ohlcs = storage_h.await.unwrap();
+        assert_eq!(ohlcs.len(), 300);
+        assert_eq!(ohlcs[0].start, 1717171200);
+        assert_eq!(ohlcs[0].open.to_string(), "100.5000");
+        assert_eq!(ohlcs[0].close.to_string(), "99.0000");
+        assert_eq!(ohlcs[299].start, 1717171200 + 299 * 60);
+        assert_eq!(ohlcs[299].close.to_string(), "398.0000");
+    }
+
+    /// History is loaded from local HTTP endpoint for every asset.
+    #[tokio::test]
+    async fn test_replay_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0u8; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).to_string();
+
+            let body = r#"{"data":[{"priceUsd":"2.5","time":120000},{"priceUsd":"1.5","time":60000}]}"#;
+            let response = format!(concat!("HTTP/1.1 200 OK\r\n",
+                "Content-Type: application/json\r\n",
+                "Content-Length: {}\r\n",
+                "Connection: close\r\n\r\n{}"
+            ), body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            request
+        });
+
+        let btc = Symbol::new("BTC").unwrap();
+        let assets = [Asset { id: "bitcoin".to_string(), symbol: Some(btc) }];
+        let (tx, _rx) = mpsc::channel(10);
+
+        let location = format!("http://{}/v2/assets/{{id}}/history", addr);
+        let replay = Replay::new(&location, ReplayFormat::CoinCapHistory, &assets,
+            tx
+        );
+
+        let infos = replay.load().await.unwrap();
+        let rates: Vec<_> = infos.iter()
+            .map(|i| (i.timestamp, i.rate.unwrap().to_string()))
+            .collect();
+        assert_eq!(rates, vec![(60, "1.5".to_string()), (120, "2.5".to_string())]);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /v2/assets/bitcoin/history "));
+    }
+}
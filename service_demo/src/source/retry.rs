@@ -0,0 +1,203 @@
+//! Retry policy and circuit breaker for polling collectors.
+//!
+//! Failed requests are not retried immediately, next request is delayed by
+//! backoff that depends on error class:
+//! - transient errors, i.e. connection reset or status 503, are likely to go
+//!   away soon, so backoff starts short,
+//! - permanent errors, i.e. status 404 or response that can not be decoded,
+//!   are unlikely to be fixed without human intervention, so backoff starts
+//!   long.
+//!
+//! Backoff grows while failures repeat. After `failures_max` consecutive
+//! failures circuit breaker opens and no requests are made for `cooldown`.
+//! Then single probe request is made, if it succeeds, breaker is closed,
+//! otherwise it is opened again.
+
+
+
+use std::time::Duration;
+
+
+
+/// Exponential backoff range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+
+
+impl Backoff {
+    /// Delay after given number of consecutive failures, it is doubled after
+    /// each failure.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(31);
+
+        self.min.saturating_mul(1 << exp).min(self.max)
+    }
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Transient,
+    Permanent,
+}
+
+
+
+/// Retry configuration.
+///
+/// `failures_max` - number of consecutive failures that opens breaker.
+/// `cooldown` - how long breaker stays open before probe request is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub transient: Backoff,
+    pub permanent: Backoff,
+    pub failures_max: u32,
+    pub cooldown: Duration,
+}
+
+
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            transient: Backoff {
+                min: Duration::from_secs(1),
+                max: Duration::from_secs(30),
+            },
+            permanent: Backoff {
+                min: Duration::from_secs(10),
+                max: Duration::from_secs(300),
+            },
+            failures_max: 10,
+            cooldown: Duration::from_secs(120),
+        }
+    }
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are made normally.
+    Closed,
+    /// Requests are suspended for cooldown.
+    Open,
+    /// Cooldown has passed, next request is a probe.
+    HalfOpen,
+}
+
+
+
+/// Tracks consecutive failures of single collector.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    policy: RetryPolicy,
+    failures: u32,
+    state: BreakerState,
+}
+
+
+
+impl Retry {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+            state: BreakerState::Closed,
+        }
+    }
+
+
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+
+
+    /// Number of consecutive failures.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+
+
+    /// Request is about to be made. If breaker is open, cooldown has passed
+    /// and this request is a probe.
+    pub fn attempt(&mut self) {
+        if self.state == BreakerState::Open {
+            self.state = BreakerState::HalfOpen;
+        }
+    }
+
+
+
+    /// Request has succeeded, breaker is closed.
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.state = BreakerState::Closed;
+    }
+
+
+
+    /// Request has failed, returns minimal delay before next request.
+    pub fn failure(&mut self, class: ErrorClass) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+
+        if self.state == BreakerState::HalfOpen
+            || self.failures >= self.policy.failures_max
+        {
+            self.state = BreakerState::Open;
+            return self.policy.cooldown
+        }
+
+        match class {
+            ErrorClass::Transient => self.policy.transient.delay(self.failures),
+            ErrorClass::Permanent => self.policy.permanent.delay(self.failures),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let secs = Duration::from_secs;
+        let mut retry = Retry::new(RetryPolicy {
+            transient: Backoff { min: secs(1), max: secs(4) },
+            permanent: Backoff { min: secs(10), max: secs(60) },
+            failures_max: 5,
+            cooldown: secs(120),
+        });
+
+        // Backoff grows with consecutive failures of any class.
+        let delays: Vec<_> = [ErrorClass::Transient, ErrorClass::Transient,
+            ErrorClass::Permanent, ErrorClass::Transient
+        ].into_iter().map(|class| retry.failure(class)).collect();
+        assert_eq!(delays, vec![secs(1), secs(2), secs(40), secs(4)]);
+        assert_eq!(retry.state(), BreakerState::Closed);
+
+        // Breaker opens, failed probe opens it again.
+        assert_eq!(retry.failure(ErrorClass::Transient), secs(120));
+        assert_eq!(retry.state(), BreakerState::Open);
+        retry.attempt();
+        assert_eq!(retry.state(), BreakerState::HalfOpen);
+        assert_eq!(retry.failure(ErrorClass::Transient), secs(120));
+        assert_eq!(retry.state(), BreakerState::Open);
+
+        // Successful probe closes breaker and resets backoff.
+        retry.attempt();
+        retry.success();
+        assert_eq!((retry.state(), retry.failures()), (BreakerState::Closed, 0));
+        assert_eq!(retry.failure(ErrorClass::Transient), secs(1));
+    }
+}
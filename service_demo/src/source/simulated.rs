@@ -0,0 +1,596 @@
+//! Source that generates synthetic rates.
+//!
+//! Rates follow either arithmetic random walk or geometric Brownian motion,
+//! optionally with gaps (periods without ticks) and outliers (single ticks
+//! far away from the path). Generator is seeded, so the same configuration
+//! always produces the same ticks with the same timestamps, which allows to
+//! run the whole pipeline offline and write reproducible tests.
+//!
+//! Random number generator is implemented here instead of using external
+//! crate, so that output does not change between dependency versions.
+
+
+
+use std::{
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::{
+        Duration,
+        UNIX_EPOCH,
+    },
+};
+
+use tokio::{
+    sync::mpsc,
+    time::{
+        interval,
+        MissedTickBehavior,
+    },
+};
+
+use async_trait::async_trait;
+
+use rate_limit::{
+    clock::{
+        Clock,
+        SystemClock,
+    },
+    config::env_parse,
+};
+
+use crate::{
+    shared_state::SharedState,
+    price::Price,
+    price_info::PriceInfo,
+    source::Source,
+    symbol::{
+        Asset,
+        Symbol,
+    },
+};
+
+
+
+/// Number of decimal places of generated rates.
+const SCALE: u8 = 8;
+
+/// Seconds in a year, volatility and drift are annualized.
+const YEAR_SECS: f64 = 365.25 * 86400.0;
+
+
+
+/// Price path model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    /// Arithmetic random walk, step size does not depend on current price.
+    RandomWalk,
+    /// Geometric Brownian motion, step size is proportional to price.
+    #[default]
+    Gbm,
+}
+
+
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "random_walk" => Ok(Self::RandomWalk),
+            "gbm" => Ok(Self::Gbm),
+            _ => Err(format!("unknown simulation model: {}", s)),
+        }
+    }
+}
+
+
+
+/// Simulation parameters.
+///
+/// `ts_start` - Unix timestamp in milliseconds of the first tick, if it is
+/// None, current time is used.
+/// `tick_period` - simulated time between ticks.
+/// `ticks` - number of ticks after which source stops, None runs forever.
+/// `realtime` - if set, source waits `tick_period` between ticks, otherwise
+/// ticks are generated as fast as they can be processed.
+/// `volatility`, `drift` - annualized, i.e. volatility 0.8 is 80% per year.
+/// `gap_probability` - probability that gap of `gap_ticks` starts at a tick.
+/// `outlier_probability` - probability that tick is moved away from path by
+/// `outlier_factor`, i.e. 0.1 moves it by 10% up or down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedConfig {
+    pub seed: u64,
+    pub model: Model,
+    pub ts_start: Option<u64>,
+    pub tick_period: Duration,
+    pub ticks: Option<u64>,
+    pub realtime: bool,
+    pub rate_start: f64,
+    pub volatility: f64,
+    pub drift: f64,
+    pub gap_probability: f64,
+    pub gap_ticks: u32,
+    pub outlier_probability: f64,
+    pub outlier_factor: f64,
+}
+
+
+
+impl Default for SimulatedConfig {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            model: Model::default(),
+            ts_start: None,
+            tick_period: Duration::from_millis(1000),
+            ticks: None,
+            realtime: true,
+            rate_start: 100.0,
+            volatility: 0.8,
+            drift: 0.0,
+            gap_probability: 0.0,
+            gap_ticks: 0,
+            outlier_probability: 0.0,
+            outlier_factor: 0.0,
+        }
+    }
+}
+
+
+
+impl SimulatedConfig {
+    /// Load configuration from ENV, parameters that are not set keep default
+    /// values.
+    ///
+    /// `SIM_TS_START` - Unix timestamp in milliseconds of the first tick, 0
+    /// uses current time.
+    /// `SIM_REALTIME` - whether ticks are paced by `SIM_TICK_MILLIS`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut cfg = Self::default();
+
+        let mut tick_millis = cfg.tick_period.as_millis() as u64;
+        let mut ticks = 0u64;
+        let mut ts_start = 0u64;
+
+        env_parse("SIM_SEED", &mut cfg.seed)?;
+        env_parse("SIM_MODEL", &mut cfg.model)?;
+        env_parse("SIM_TS_START", &mut ts_start)?;
+        env_parse("SIM_TICK_MILLIS", &mut tick_millis)?;
+        env_parse("SIM_TICKS", &mut ticks)?;
+        env_parse("SIM_REALTIME", &mut cfg.realtime)?;
+        env_parse("SIM_RATE", &mut cfg.rate_start)?;
+        env_parse("SIM_VOLATILITY", &mut cfg.volatility)?;
+        env_parse("SIM_DRIFT", &mut cfg.drift)?;
+        env_parse("SIM_GAP_PROBABILITY", &mut cfg.gap_probability)?;
+        env_parse("SIM_GAP_TICKS", &mut cfg.gap_ticks)?;
+        env_parse("SIM_OUTLIER_PROBABILITY", &mut cfg.outlier_probability)?;
+        env_parse("SIM_OUTLIER_FACTOR", &mut cfg.outlier_factor)?;
+
+        if tick_millis == 0 {
+            return Err("SIM_TICK_MILLIS must be positive".to_string())
+        }
+
+        if !cfg.rate_start.is_finite() || cfg.rate_start <= 0.0 {
+            return Err("SIM_RATE must be positive".to_string())
+        }
+
+        cfg.tick_period = Duration::from_millis(tick_millis);
+        cfg.ticks = (ticks > 0).then_some(ticks);
+        cfg.ts_start = (ts_start > 0).then_some(ts_start);
+
+        Ok(cfg)
+    }
+}
+
+
+
+/// SplitMix64 generator, small and good enough for simulation.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+        z ^ (z >> 31)
+    }
+
+
+
+    /// Uniform value in [0, 1).
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+
+
+    /// Standard normal value, Box-Muller transform.
+    fn normal(&mut self) -> f64 {
+        // 1 - uniform is in (0, 1], so logarithm is finite.
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+
+
+/// Price path of single asset.
+#[derive(Debug, Clone)]
+struct Path {
+    base: Symbol,
+    rng: Rng,
+    rate: f64,
+    gap_left: u32,
+}
+
+
+
+impl Path {
+    /// Advance path by one tick, returns rate that is emitted or None if tick
+    /// falls into gap.
+    fn step(&mut self, cfg: &SimulatedConfig) -> Option<f64> {
+        let dt = cfg.tick_period.as_secs_f64() / YEAR_SECS;
+        let z = self.rng.normal();
+
+        // Random numbers are always drawn in the same order, so that enabling
+        // gaps or outliers does not change the path itself.
+        let gap = self.rng.uniform() < cfg.gap_probability;
+        let outlier = self.rng.uniform() < cfg.outlier_probability;
+        let outlier_up = self.rng.uniform() < 0.5;
+
+        match cfg.model {
+            Model::RandomWalk => {
+                let step = cfg.rate_start * (cfg.drift * dt + cfg.volatility * dt.sqrt() * z);
+
+                // Price can not go below zero, keep it at smallest unit.
+                self.rate = (self.rate + step).max(1.0 / 10f64.powi(SCALE as i32));
+            }
+            Model::Gbm => {
+                let sigma = cfg.volatility;
+                let exp = (cfg.drift - sigma * sigma / 2.0) * dt + sigma * dt.sqrt() * z;
+
+                self.rate *= exp.exp();
+            }
+        }
+
+        if self.gap_left > 0 {
+            self.gap_left -= 1;
+            return None
+        }
+
+        if gap && cfg.gap_ticks > 0 {
+            self.gap_left = cfg.gap_ticks - 1;
+            return None
+        }
+
+        if outlier {
+            let factor = 1.0 + cfg.outlier_factor;
+
+            return Some(if outlier_up { self.rate * factor } else { self.rate / factor })
+        }
+
+        Some(self.rate)
+    }
+}
+
+
+
+// Convert generated rate into Price, None if it does not fit.
+fn price(rate: f64) -> Option<Price> {
+    let value = (rate * 10f64.powi(SCALE as i32)).round();
+
+    if !value.is_finite() || value < 0.0 || value >= u64::MAX as f64 {
+        return None
+    }
+
+    Price::new(value as u64, SCALE).ok()
+}
+
+
+
+/// Source that generates rates of all configured assets against USD.
+///
+/// Every asset has its own path, seeded from configured seed and asset
+/// position, so adding asset to the end of the list does not change paths of
+/// other assets. If asset symbol is not configured, uppercase asset id is
+/// used. If start timestamp is not configured, `clock` is read on start.
+pub struct Simulated {
+    tx: mpsc::Sender<PriceInfo>,
+    cfg: SimulatedConfig,
+    paths: Vec<Path>,
+    clock: Arc<dyn Clock>,
+}
+
+
+
+impl Simulated {
+    pub fn new(cfg: SimulatedConfig, assets: &[Asset],
+        tx: mpsc::Sender<PriceInfo>
+    )
+        -> Result<Self, String>
+    {
+        let mut paths = Vec::with_capacity(assets.len());
+
+        for (i, asset) in assets.iter().enumerate() {
+            let base = match asset.symbol {
+                Some(base) => base,
+                None => Symbol::new(&asset.id).map_err(|e| e.to_string())?,
+            };
+
+            // Mix seed, so that paths of neighbouring seeds and assets are not
+            // correlated.
+            let mut seed = Rng(cfg.seed ^ (i as u64).wrapping_mul(0xd1b54a32d192ed03));
+            let rng = Rng(seed.next_u64());
+
+            paths.push(Path {
+                base,
+                rng,
+                rate: cfg.rate_start,
+                gap_left: 0,
+            });
+        }
+
+        Ok(Self {
+            tx,
+            cfg,
+            paths,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+
+
+#[async_trait]
+impl Source for Simulated {
+    async fn main(mut self, shared_state: Arc<SharedState>) {
+        let ts_start = self.cfg.ts_start.unwrap_or_else(|| {
+            self.clock.now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0)
+        });
+        let tick_millis = self.cfg.tick_period.as_millis() as u64;
+
+        let mut ticker = interval(self.cfg.tick_period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut tick: u64 = 0;
+        while self.cfg.ticks.map_or(true, |ticks| tick < ticks) {
+            if shared_state.shut_down.load(Ordering::Relaxed) != 0 {
+                return
+            }
+
+            if self.cfg.realtime {
+                ticker.tick().await;
+            }
+
+            let timestamp = (ts_start + tick * tick_millis) / 1000;
+            tick += 1;
+
+            for path in &mut self.paths {
+                let Some(rate) = path.step(&self.cfg) else { continue };
+
+                let info = PriceInfo::new(timestamp, path.base, Symbol::USD,
+                    price(rate)
+                );
+
+                // Generated data is never dropped, so that output is the same
+                // regardless of how fast backend is.
+                if self.tx.send(info).await.is_err() {
+                    return
+                }
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ohlc::Ohlc,
+        ohlc_calc::{
+            self,
+            OhlcCalc,
+            OhlcMap,
+        },
+        atomic_swap::AtomicSwap,
+        source,
+    };
+
+    fn config() -> SimulatedConfig {
+        SimulatedConfig {
+            seed: 42,
+            ts_start: Some(1717171200000),
+            tick_period: Duration::from_millis(500),
+            ticks: Some(600),
+            realtime: false,
+            rate_start: 67000.0,
+            ..SimulatedConfig::default()
+        }
+    }
+
+    fn assets() -> Vec<Asset> {
+        vec![
+            Asset { id: "bitcoin".to_string(), symbol: Some(Symbol::new("BTC").unwrap()) },
+            Asset { id: "eth".to_string(), symbol: None },
+        ]
+    }
+
+    async fn ticks(cfg: SimulatedConfig) -> Vec<PriceInfo> {
+        let (tx, mut rx) = mpsc::channel(10);
+        let sim = Simulated::new(cfg, &assets(), tx).unwrap();
+
+        let h = tokio::spawn(source::main(sim, Arc::new(SharedState::default())));
+
+        let mut ret = Vec::new();
+        while let Some(info) = rx.recv().await {
+            ret.push(info);
+        }
+        h.await.unwrap();
+
+        ret
+    }
+
+    /// Start of simulation and pacing are configured from ENV as well, so
+    /// that run driven by .env is deterministic.
+    #[test]
+    fn test_from_env() {
+        std::env::set_var("SIM_TS_START", "1717171200000");
+        std::env::set_var("SIM_REALTIME", "false");
+
+        let cfg = SimulatedConfig::from_env().unwrap();
+        assert_eq!((cfg.ts_start, cfg.realtime), (Some(1717171200000), false));
+
+        std::env::set_var("SIM_REALTIME", "no");
+        assert_eq!(SimulatedConfig::from_env(),
+            Err("SIM_REALTIME is not valid: no".to_string())
+        );
+
+        std::env::remove_var("SIM_TS_START");
+        std::env::remove_var("SIM_REALTIME");
+    }
+
+    /// Start timestamp that is not configured is read from clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_ts_start_clock() {
+        let clock = Arc::new(rate_limit::clock::MockClock::new(
+            UNIX_EPOCH + Duration::from_millis(1717171200600)
+        ));
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let cfg = SimulatedConfig { ts_start: None, ticks: Some(2), ..config() };
+        let mut sim = Simulated::new(cfg, &assets(), tx).unwrap();
+        sim.clock_set(clock);
+
+        source::main(sim, Arc::new(SharedState::default())).await;
+
+        let mut timestamps = Vec::new();
+        while let Some(info) = rx.recv().await {
+            timestamps.push(info.timestamp);
+        }
+        assert_eq!(timestamps, vec![1717171210, 1717171210, 1717171211, 1717171211]);
+    }
+
+    fn rates(infos: &[PriceInfo]) -> Vec<(u64, Symbol, Option<Price>)> {
+        infos.iter().map(|i| (i.timestamp, i.base, i.rate)).collect()
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut rng = Rng(7);
+        let n = 10000;
+        let values: Vec<f64> = (0..n).map(|_| rng.normal()).collect();
+
+        let mean = values.iter().sum::<f64>() / n as f64;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n as f64;
+
+        assert!(mean.abs() < 0.05, "{}", mean);
+        assert!((var - 1.0).abs() < 0.05, "{}", var);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic() {
+        let a = ticks(config()).await;
+        let b = ticks(config()).await;
+
+        assert_eq!(a.len(), 1200);
+        assert_eq!(rates(&a), rates(&b));
+        assert_eq!(a[0].timestamp, 1717171200);
+        assert_eq!(a[1].base.as_str(), "ETH");
+        assert_eq!(a[1199].timestamp, 1717171200 + 299);
+
+        // Assets have different paths.
+        assert_ne!(a[2].rate, a[3].rate);
+
+        let c = ticks(SimulatedConfig { seed: 43, ..config() }).await;
+        assert_ne!(rates(&a), rates(&c));
+    }
+
+    #[tokio::test]
+    async fn test_gaps_outliers() {
+        let plain = ticks(SimulatedConfig { model: Model::RandomWalk, ..config() })
+            .await;
+        let noisy = ticks(SimulatedConfig {
+            model: Model::RandomWalk,
+            gap_probability: 0.02,
+            gap_ticks: 10,
+            outlier_probability: 0.02,
+            outlier_factor: 0.1,
+            ..config()
+        }).await;
+
+        assert!(noisy.len() < plain.len());
+
+        // Path is the same, so every emitted tick is either equal to plain
+        // tick or is an outlier that is 10% away from it.
+        let mut outliers = 0;
+        for info in &noisy {
+            let same = plain.iter()
+                .find(|p| p.base == info.base && p.timestamp == info.timestamp
+                    && p.rate == info.rate
+                );
+
+            if same.is_none() {
+                outliers += 1;
+            }
+        }
+
+        assert!(outliers > 0);
+        assert!(outliers < noisy.len() / 10);
+    }
+
+    /// Whole pipeline produces the same candles on every run.
+    #[tokio::test]
+    async fn test_pipeline_reproducible() {
+        async fn candles() -> Vec<String> {
+            let (tx, rx) = mpsc::channel(10);
+            let (tx_storage, mut rx_storage) = mpsc::channel::<Ohlc>(100);
+            let terminal = Arc::new(AtomicSwap::new(Box::new(Some(OhlcMap::new()))));
+
+            let sim = Simulated::new(config(), &assets(), tx).unwrap();
+            let mut calc = OhlcCalc::new(rx, tx_storage, terminal);
+            calc.timeframes_set(vec![60, 300]);
+
+            let state = Arc::new(SharedState::default());
+            let calc_h = tokio::spawn(ohlc_calc::main(calc, state.clone()));
+            source::main(sim, state).await;
+            calc_h.await.unwrap();
+
+            let mut ret = Vec::new();
+            while let Some(ohlc) = rx_storage.recv().await {
+                ret.push(ohlc.to_string());
+            }
+
+            ret
+        }
+
+        let a = candles().await;
+
+        // 5 minutes of data, last 1 minute and 5 minute candles are not
+        // finished for both assets.
+        assert_eq!(a.len(), 8);
+        assert_eq!(a, candles().await);
+    }
+}
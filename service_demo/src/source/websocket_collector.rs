@@ -0,0 +1,556 @@
+//! Collector that receives rates pushed over WebSocket stream.
+//!
+//! Unlike HTTP polling, single connection is used for all configured assets
+//! and every update pushed by endpoint is forwarded, so there is no request
+//! overhead and no sampling. Connection is kept alive with pings, if it is
+//! lost, collector reconnects with exponential backoff and subscribes again.
+//!
+//! Subscription and message format are described by StreamSchema
+//! implementation, see coincap.rs and binance.rs.
+
+
+
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpStream,
+    sync::mpsc,
+    time::{
+        interval,
+        timeout,
+        MissedTickBehavior,
+    },
+};
+
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        self,
+        Message,
+    },
+    MaybeTlsStream,
+    WebSocketStream,
+};
+
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+
+use async_trait::async_trait;
+
+use rate_limit::clock::{
+    Clock,
+    SystemClock,
+};
+
+use crate::{
+    shared_state::SharedState,
+    price_info::PriceInfo,
+    decimal::Rounding,
+    source::{
+        Source,
+        async_http_collector::DecodeError,
+    },
+    symbol::Asset,
+};
+
+
+
+/// How often connection state and shut down flag are checked.
+const CHECK_PERIOD: Duration = Duration::from_millis(250);
+
+
+
+/// Describes subscription and message format of WebSocket rates stream.
+pub trait StreamSchema: Send + Sync + 'static {
+    /// Connection URL for given assets, `url` is endpoint URL from
+    /// configuration.
+    fn url(&self, url: &str, assets: &[Asset]) -> String;
+
+    /// Messages that must be sent after each connect to receive updates for
+    /// given assets.
+    fn subscribe(&self, assets: &[Asset]) -> Vec<String>;
+
+    /// Decode pushed text message. Message may contain any number of ticks,
+    /// service messages, i.e. subscription confirmations, decode into none.
+    ///
+    /// `clock` - source of local time, if stream does not return timestamps.
+    fn decode(&self, msg: &str, assets: &[Asset], rounding: Rounding,
+        clock: &dyn Clock
+    )
+        -> Result<Vec<PriceInfo>, DecodeError>;
+}
+
+
+
+/// Reason why streaming session has ended.
+#[derive(Debug)]
+pub enum StreamError {
+    Ws(tungstenite::Error),
+    /// Connection was closed by endpoint.
+    Closed,
+    /// Nothing was received, not even pong, for too long.
+    Timeout,
+}
+
+
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ws(e) => write!(f, "{}", e),
+            Self::Closed => write!(f, "connection closed by endpoint"),
+            Self::Timeout => write!(f, "connection timed out"),
+        }
+    }
+}
+
+
+
+impl From<tungstenite::Error> for StreamError {
+    fn from(e: tungstenite::Error) -> Self {
+        Self::Ws(e)
+    }
+}
+
+
+
+/// Collector that receives rates of all configured assets over single
+/// WebSocket connection.
+///
+/// `ping_period` - if nothing is received for this long, ping is sent. If
+/// still nothing is received in next period, connection is considered dead.
+/// `connect_timeout` - time limit to connect and complete handshake, connect
+/// that takes longer is failed and backed off.
+/// `backoff_min`, `backoff_max` - delay before reconnect, it is doubled after
+/// each unsuccessful connection and reset once data is received.
+/// `clock` - source of time for heartbeat, reconnects and tick timestamps.
+pub struct WebSocketCollector<S> {
+    schema: S,
+    tx: mpsc::Sender<PriceInfo>,
+    url: String,
+    assets: Vec<Asset>,
+    rounding: Rounding,
+    ping_period: Duration,
+    connect_timeout: Duration,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+
+
+impl<S: StreamSchema> WebSocketCollector<S> {
+    /// Create collector for given assets, `url` is endpoint URL from
+    /// configuration, connection URL is built by schema.
+    pub fn new(schema: S, url: &str, assets: &[Asset],
+        tx: mpsc::Sender<PriceInfo>
+    )
+        -> Self
+    {
+        Self {
+            url: schema.url(url, assets),
+            schema,
+            tx,
+            assets: assets.to_vec(),
+            rounding: Rounding::default(),
+            ping_period: Duration::from_secs(15),
+            connect_timeout: Duration::from_secs(10),
+            backoff_min: Duration::from_millis(500),
+            backoff_max: Duration::from_secs(30),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+
+
+    /// Set rounding mode for rates that have more decimal places than can be
+    /// stored.
+    pub fn rounding_set(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+
+
+    /// Set ping period in milliseconds.
+    pub fn ping_period_millis_set(&mut self, ping_period: u64) {
+        self.ping_period = Duration::from_millis(ping_period);
+    }
+
+
+
+    /// Set connect timeout in milliseconds.
+    pub fn connect_timeout_millis_set(&mut self, connect_timeout: u64) {
+        self.connect_timeout = Duration::from_millis(connect_timeout);
+    }
+
+
+
+    /// Set minimal and maximal reconnect delay in milliseconds.
+    pub fn backoff_millis_set(&mut self, backoff_min: u64, backoff_max: u64) {
+        self.backoff_min = Duration::from_millis(backoff_min);
+        self.backoff_max = Duration::from_millis(backoff_max.max(backoff_min));
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+
+
+    // Receive messages until connection is lost or shut down is requested.
+    //
+    // Backoff is reset as soon as first tick is received, so that only
+    // connections that do not deliver any data are backed off.
+    async fn session(&self, ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        shared_state: &SharedState, backoff: &mut Duration
+    )
+        -> Result<(), StreamError>
+    {
+        for msg in self.schema.subscribe(&self.assets) {
+            ws.send(Message::Text(msg)).await?;
+        }
+
+        let mut check = interval(CHECK_PERIOD);
+        check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut ts_received = self.clock.instant();
+        let mut ts_ping = ts_received;
+
+        loop {
+            tokio::select! {
+                msg = ws.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        None => return Err(StreamError::Closed),
+                    };
+
+                    ts_received = self.clock.instant();
+
+                    match msg {
+                        Message::Text(text) => {
+                            let received = self.forward(&text);
+
+                            if received {
+                                *backoff = self.backoff_min;
+                            }
+                        }
+                        Message::Ping(data) => ws.send(Message::Pong(data)).await?,
+                        Message::Close(..) => return Err(StreamError::Closed),
+                        _ => {}
+                    }
+                }
+
+                _ = check.tick() => {
+                    if shared_state.shut_down.load(Ordering::Relaxed) != 0 {
+                        // Nothing is lost if close handshake fails, we are
+                        // leaving anyway.
+                        let _ = ws.close(None).await;
+                        return Ok(())
+                    }
+
+                    let now = self.clock.instant();
+                    let silence = now - ts_received;
+
+                    if silence >= self.ping_period * 2 {
+                        return Err(StreamError::Timeout)
+                    }
+
+                    if silence >= self.ping_period && now - ts_ping >= self.ping_period {
+                        ws.send(Message::Ping(Vec::new())).await?;
+                        ts_ping = now;
+                    }
+                }
+            }
+        }
+    }
+
+
+
+    // Decode text message and send ticks to channel. Returns true if message
+    // contained at least one tick.
+    fn forward(&self, text: &str) -> bool {
+        let result = self.schema.decode(text, &self.assets, self.rounding,
+            self.clock.as_ref()
+        );
+
+        let infos = match result {
+            Ok(infos) => infos,
+            Err(e) => {
+                eprintln!("ERROR: could not decode message from {}: {}",
+                    self.url, e
+                );
+
+                return false
+            }
+        };
+
+        let received = !infos.is_empty();
+
+        for info in infos {
+            // Same as for HTTP collector, data is lost if backend can not
+            // keep up, because only real time data is valuable.
+            if self.tx.try_send(info).is_err() {
+                eprintln!(concat!("ERROR: backend can not process",
+                    " incomming data fast enough, dropping packet."
+                ));
+            }
+        }
+
+        received
+    }
+}
+
+
+
+#[async_trait]
+impl<S: StreamSchema> Source for WebSocketCollector<S> {
+    async fn main(self, shared_state: Arc<SharedState>) {
+        let mut backoff = self.backoff_min;
+
+        while shared_state.shut_down.load(Ordering::Relaxed) == 0 {
+            // Endpoint that accepts connection but never answers handshake
+            // must not stall collector.
+            match timeout(self.connect_timeout, connect_async(&self.url)).await {
+                Ok(Ok((mut ws, _))) => {
+                    let result = self.session(&mut ws, &shared_state, &mut backoff)
+                        .await;
+
+                    if let Err(e) = result {
+                        eprintln!("WARNING: stream {} interrupted: {}", self.url, e);
+                    }
+                }
+
+                Ok(Err(e)) => {
+                    eprintln!("ERROR: could not connect to {}: {}", self.url, e);
+                }
+
+                Err(..) => {
+                    eprintln!("ERROR: could not connect to {}: timed out after {} ms",
+                        self.url, self.connect_timeout.as_millis()
+                    );
+                }
+            }
+
+            // Sleep in short steps, so that shut down is not delayed by long
+            // backoff.
+            let mut remaining = backoff;
+            while !remaining.is_zero() {
+                if shared_state.shut_down.load(Ordering::Relaxed) != 0 {
+                    return
+                }
+
+                let step = remaining.min(CHECK_PERIOD);
+                self.clock.sleep_until(self.clock.instant() + step).await;
+                remaining -= step;
+            }
+
+            backoff = (backoff * 2).min(self.backoff_max);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        net::TcpListener,
+        time::timeout,
+    };
+    use std::time::UNIX_EPOCH;
+    use tokio_tungstenite::accept_async;
+    use rate_limit::clock::MockClock;
+    use crate::{
+        source::{
+            binance::Binance,
+            coincap::CoinCap,
+        },
+        symbol::Symbol,
+    };
+
+    /// Stand-in server pings client, sends one trade per connection and
+    /// closes it, so collector must answer pings, reconnect and subscribe
+    /// again.
+    #[tokio::test]
+    async fn test_reconnect_resubscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut subscribes = Vec::new();
+
+            for price in ["100.5", "101.25"] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(stream).await.unwrap();
+
+                let Some(Ok(Message::Text(msg))) = ws.next().await else {
+                    panic!("subscribe message expected")
+                };
+                subscribes.push(msg);
+
+                ws.send(Message::Ping(b"hb".to_vec())).await.unwrap();
+                let Some(Ok(Message::Pong(data))) = ws.next().await else {
+                    panic!("pong expected")
+                };
+                assert_eq!(data, b"hb");
+
+                let trade = format!(concat!(r#"{{"e":"trade","E":1717171717200,"#,
+                    r#""s":"BTCUSDT","p":"{}","q":"0.5","T":1717171717123}}"#
+                ), price);
+                ws.send(Message::Text(trade)).await.unwrap();
+                ws.close(None).await.unwrap();
+            }
+
+            subscribes
+        });
+
+        let btc = Symbol::new("BTC").unwrap();
+        let assets = [Asset { id: "btcusdt".to_string(), symbol: Some(btc) }];
+        let (tx, mut rx) = mpsc::channel(10);
+
+        let mut collector = WebSocketCollector::new(Binance,
+            &format!("ws://{}", addr), &assets, tx
+        );
+        collector.backoff_millis_set(10, 100);
+
+        let state = Arc::new(SharedState::default());
+        let collector_h = tokio::spawn(collector.main(state.clone()));
+
+        for price in ["100.5", "101.25"] {
+            let info = timeout(Duration::from_secs(5), rx.recv()).await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!((info.base, info.quote.as_str(), info.timestamp),
+                (btc, "USDT", 1717171717)
+            );
+            assert_eq!(info.rate.unwrap().to_string(), price);
+        }
+
+        let subscribes = timeout(Duration::from_secs(5), server).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(subscribes.len(), 2);
+        assert_eq!(subscribes[0], subscribes[1]);
+
+        state.shut_down.store(1, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), collector_h).await.unwrap().unwrap();
+    }
+
+    /// Stand-in server accepts connections, but never answers handshake, so
+    /// connect times out and is retried after backoff.
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            let mut accepted = Vec::new();
+
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.push(stream);
+                accepted.push(tokio::time::Instant::now());
+            }
+
+            accepted[1] - accepted[0]
+        });
+
+        let assets = [Asset { id: "btcusdt".to_string(), symbol: None }];
+        let (tx, _rx) = mpsc::channel(10);
+
+        let mut collector = WebSocketCollector::new(Binance,
+            &format!("ws://{}", addr), &assets, tx
+        );
+        collector.connect_timeout_millis_set(300);
+        collector.backoff_millis_set(200, 200);
+
+        let state = Arc::new(SharedState::default());
+        let collector_h = tokio::spawn(collector.main(state.clone()));
+
+        let retry = timeout(Duration::from_secs(5), server).await
+            .unwrap()
+            .unwrap();
+        assert!(retry >= Duration::from_millis(500), "{:?}", retry);
+        assert!(retry < Duration::from_millis(1500), "{:?}", retry);
+
+        state.shut_down.store(1, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), collector_h).await.unwrap().unwrap();
+    }
+
+    /// Stand-in server sends one tick and then stops reading, collector must
+    /// ping it after ping period, drop connection after two periods of
+    /// silence and reconnect after backoff. Ticks are timestamped by mock
+    /// clock, that starts far from system time.
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let connected = clock.now();
+
+            ws.send(Message::Text(r#"{"bitcoin":"100"}"#.to_string())).await
+                .unwrap();
+
+            let Some(Ok(Message::Ping(..))) = ws.next().await else {
+                panic!("ping expected")
+            };
+            let pinged = clock.now();
+
+            // Pong is not sent, because messages are not read anymore.
+            let _ = listener.accept().await.unwrap();
+            let reconnected = clock.now();
+
+            (pinged.duration_since(connected).unwrap(),
+                reconnected.duration_since(connected).unwrap()
+            )
+        });
+
+        let btc = Symbol::new("BTC").unwrap();
+        let assets = [Asset { id: "bitcoin".to_string(), symbol: Some(btc) }];
+        let (tx, mut rx) = mpsc::channel(10);
+
+        let mut collector = WebSocketCollector::new(CoinCap,
+            &format!("ws://{}/prices", addr), &assets, tx
+        );
+        collector.ping_period_millis_set(500);
+        collector.backoff_millis_set(300, 300);
+        collector.clock_set(Arc::new(clock));
+
+        let state = Arc::new(SharedState::default());
+        let collector_h = tokio::spawn(collector.main(state.clone()));
+
+        let info = timeout(Duration::from_secs(5), rx.recv()).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.timestamp, 1_700_000_000);
+
+        let (pinged, reconnected) = timeout(Duration::from_secs(5), server).await
+            .unwrap()
+            .unwrap();
+        let millis = |d: Duration| d.as_millis();
+        assert!((500..900).contains(&millis(pinged)), "{:?}", pinged);
+        assert!((1300..2000).contains(&millis(reconnected)), "{:?}", reconnected);
+
+        state.shut_down.store(1, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), collector_h).await.unwrap().unwrap();
+    }
+}
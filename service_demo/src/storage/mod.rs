@@ -1,5 +1,6 @@
 pub mod postgres;
 pub mod stdout;
+pub mod quarantine;
 
 use std::sync::Arc;
 use crate::shared_state::SharedState;
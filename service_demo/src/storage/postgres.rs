@@ -110,6 +110,12 @@ impl Postgres {
 
 
     // Insert OHLC information into Postgresql DB if connection is available.
+    //
+    // Real Ohlc that is already stored is kept, so that replayed history can
+    // be backfilled any number of times, but it replaces synthetic Ohlc that
+    // gap filling has stored for period whose ticks were missing. Ohlc
+    // amended by late ticks, that has revision above 0, always replaces
+    // stored row.
     async fn insert_ohlc(&mut self, ohlc: Ohlc) -> Result<(), ()> {
         self.connection_ensure().await;
 
@@ -118,14 +124,43 @@ impl Postgres {
             return Err(())
         };
 
-        let sql = r#"
-            insert into ohlc(start, open, high, low, close, duration)
-            values(to_timestamp($1::bigint), $2, $3, $4, $5, $6)
+        let upsert = r#"
+            insert into ohlc(base, quote, start, open, high, low, close,
+                duration, synthetic, ticks, ts_first, ts_last, mean, twap,
+                volume, vwap
+            )
+            values($1, $2, to_timestamp($3::bigint), $4, $5, $6, $7, $8,
+                $9, $10, to_timestamp($11::bigint),
+                to_timestamp($12::bigint), $13, $14, $15, $16
+            )
+            on conflict (base, quote, start, duration) do update
+            set open = excluded.open, high = excluded.high,
+                low = excluded.low, close = excluded.close,
+                synthetic = excluded.synthetic, ticks = excluded.ticks,
+                ts_first = excluded.ts_first, ts_last = excluded.ts_last,
+                mean = excluded.mean, twap = excluded.twap,
+                volume = excluded.volume, vwap = excluded.vwap
         "#;
 
-        let r = client.query(sql, &[
-            &(ohlc.start as i64), &(ohlc.open as i64), &(ohlc.high as i64),
-            &(ohlc.low as i64), &(ohlc.close as i64), &(ohlc.duration as i32),
+        let sql = if ohlc.revision == 0 {
+            format!("{} where ohlc.synthetic", upsert)
+        }
+        else {
+            upsert.to_string()
+        };
+
+        let volume = ohlc.volume.map(|volume| volume.value() as i64);
+        let vwap = ohlc.vwap.map(|vwap| vwap.value() as i64);
+
+        let r = client.query(&sql, &[
+            &ohlc.base.as_str(), &ohlc.quote.as_str(),
+            &(ohlc.start as i64), &(ohlc.open.value() as i64),
+            &(ohlc.high.value() as i64), &(ohlc.low.value() as i64),
+            &(ohlc.close.value() as i64), &(ohlc.duration as i32),
+            &ohlc.synthetic, &(ohlc.ticks as i64),
+            &(ohlc.ts_first as i64), &(ohlc.ts_last as i64),
+            &(ohlc.mean.value() as i64), &(ohlc.twap.value() as i64),
+            &volume, &vwap,
         ]).await;
 
         if let Err(e) = r {
@@ -162,3 +197,63 @@ impl Storage for Postgres {
 }
 
 
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        price::Price,
+        symbol::Symbol,
+    };
+
+    /// Real Ohlc replaces synthetic row, but not real one, amended Ohlc
+    /// replaces any row.
+    ///
+    /// Needs database with migrated schema configured by `DB_*` ENV, i.e.
+    /// the one from docker-compose: `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_insert_ohlc() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut storage = Postgres::new(rx);
+
+        let base = Symbol::new("PGTEST").unwrap();
+        let ohlc = |close, synthetic, revision| {
+            let mut ohlc = Ohlc::new(base, Symbol::USD, 60, 60,
+                Price::new(close, Ohlc::SCALE).unwrap()
+            );
+            ohlc.synthetic = synthetic;
+            ohlc.revision = revision;
+            ohlc
+        };
+
+        storage.connection_ensure().await;
+        let client = storage.client.as_ref().expect("database is not available");
+        let sql_delete = "delete from ohlc where base = $1";
+        client.execute(sql_delete, &[&base.as_str()]).await.unwrap();
+
+        // Ohlc that is inserted and row that is stored after it.
+        let cases = [
+            (ohlc(100, true, 0), (100, true)),
+            (ohlc(110, false, 0), (110, false)),
+            (ohlc(120, false, 0), (110, false)),
+            (ohlc(130, false, 1), (130, false)),
+        ];
+
+        for (ohlc, stored) in cases {
+            storage.insert_ohlc(ohlc).await.unwrap();
+
+            let row = storage.client.as_ref().unwrap().query_one(
+                "select close, synthetic from ohlc where base = $1",
+                &[&base.as_str()]
+            ).await.unwrap();
+
+            assert_eq!((row.get::<_, i64>(0), row.get::<_, bool>(1)), stored);
+        }
+
+        let client = storage.client.as_ref().unwrap();
+        client.execute(sql_delete, &[&base.as_str()]).await.unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{
+    Arc,
+    atomic::Ordering,
+};
+
+use tokio::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    shared_state::SharedState,
+    storage::Storage,
+    validator::Rejected,
+};
+
+
+
+/// Sink for ticks rejected by validator, so that they can be reviewed later.
+///
+/// `rx` - receiver for rejected ticks.
+/// `path` - rejected ticks are appended to this file as CSV rows, if it is
+/// not set, they are written to STDERR.
+pub struct Quarantine {
+    rx: mpsc::Receiver<Rejected>,
+    path: Option<String>,
+}
+
+
+
+impl Quarantine {
+    pub fn new(rx: mpsc::Receiver<Rejected>) -> Self {
+        Self {
+            rx,
+            path: None,
+        }
+    }
+
+
+
+    /// Set file that rejected ticks are appended to.
+    pub fn path_set(&mut self, path: &str) {
+        self.path = Some(path.to_string());
+    }
+}
+
+
+
+async fn file_open(path: &str) -> Option<File> {
+    let r = OpenOptions::new().create(true).append(true).open(path).await;
+
+    match r {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!(concat!("ERROR: could not open quarantine file {},",
+                " writing rejected ticks to STDERR, error: {}"
+            ), path, e);
+
+            None
+        }
+    }
+}
+
+
+
+#[async_trait]
+impl Storage for Quarantine {
+    async fn main(mut self, shared_state: Arc<SharedState>) {
+        let mut file = match self.path {
+            Some(ref path) => file_open(path).await,
+            None => None,
+        };
+
+        // Validator closes channel once it stops.
+        while let Some(rejected) = self.rx.recv().await {
+            match file {
+                Some(ref mut f) => {
+                    let row = format!("{}\n", rejected);
+                    if let Err(e) = f.write_all(row.as_bytes()).await {
+                        eprintln!(concat!("ERROR: could not write quarantine",
+                            " file, error: {}"
+                        ), e);
+                        eprintln!("WARNING: quarantined tick {}", rejected);
+                    }
+                }
+                None => eprintln!("WARNING: quarantined tick {}", rejected),
+            }
+
+            let intr = shared_state.shut_down.load(Ordering::Relaxed);
+            if intr != 0 {
+                break
+            }
+        }
+
+        if let Some(ref mut f) = file {
+            let _ = f.flush().await;
+        }
+    }
+}
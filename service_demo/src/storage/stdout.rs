@@ -38,7 +38,7 @@ impl Storage for Stdout {
     async fn main(mut self, shared_state: Arc<SharedState>) {
         // If collector thread has crashed, this thread has no use to be alive.
         while let Some(ohlc) = self.rx.recv().await {
-            println!("{:?}", ohlc);
+            println!("{}", ohlc);
 
             let intr = shared_state.shut_down.load(Ordering::Relaxed);
             if intr != 0 {
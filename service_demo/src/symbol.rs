@@ -0,0 +1,305 @@
+//! Asset and currency symbols.
+//!
+//! Symbols are not known at compile time, they are loaded from configuration
+//! at startup or taken from upstream API responses. Each distinct code is
+//! validated and interned once, so that Symbol is a cheap `Copy` value that
+//! can be passed around in every PriceInfo and Ohlc.
+//!
+//! This module also demonstrates custom serde implementation and unit tests.
+
+
+
+use std::{
+    collections::HashSet,
+    env,
+    fmt,
+    str::FromStr,
+    sync::{
+        Mutex,
+        OnceLock,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+
+
+/// Maximum length of symbol code. Longest tickers in the wild are around 10
+/// characters long, this leaves some room.
+pub const SYMBOL_LEN_MAX: usize = 16;
+
+
+
+/// Validated asset or currency code, i.e. BTC or USD.
+///
+/// Codes are always stored in uppercase.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(&'static str);
+
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolError {
+    Empty,
+    TooLong(String),
+    InvalidChar(String),
+    AssetInvalid(String),
+}
+
+
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "symbol code is empty"),
+            Self::TooLong(code) => write!(f, "symbol code is too long: {}", code),
+            Self::InvalidChar(code) => {
+                write!(f, "symbol code contains invalid characters: {}", code)
+            }
+            Self::AssetInvalid(asset) => {
+                write!(f, "invalid asset definition: {}", asset)
+            }
+        }
+    }
+}
+
+
+
+// All interned symbol codes. Strings are leaked, but set of codes is small
+// and bounded by configuration and upstream API.
+fn interned() -> &'static Mutex<HashSet<&'static str>> {
+    static INTERNED: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+
+    INTERNED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+
+
+impl Symbol {
+    /// Quote currency used by rates endpoint, it is always available.
+    pub const USD: Symbol = Symbol("USD");
+
+
+
+    /// Validate and intern symbol code.
+    ///
+    /// Code is trimmed and converted to uppercase. Only ASCII letters and
+    /// digits are allowed.
+    pub fn new(code: &str) -> Result<Self, SymbolError> {
+        let code = code.trim();
+
+        if code.is_empty() {
+            return Err(SymbolError::Empty)
+        }
+
+        if code.len() > SYMBOL_LEN_MAX {
+            return Err(SymbolError::TooLong(code.to_string()))
+        }
+
+        if !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(SymbolError::InvalidChar(code.to_string()))
+        }
+
+        let code = code.to_ascii_uppercase();
+
+        // Poisoned lock means that some thread has panicked while inserting
+        // into set, set itself is still valid.
+        let mut interned = match interned().lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        if let Some(code) = interned.get(code.as_str()) {
+            return Ok(Self(code))
+        }
+
+        let code: &'static str = Box::leak(code.into_boxed_str());
+        interned.insert(code);
+
+        Ok(Self(code))
+    }
+
+
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+}
+
+
+
+impl FromStr for Symbol {
+    type Err = SymbolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+        -> Result<Self, D::Error>
+    {
+        let code = String::deserialize(deserializer)?;
+
+        Self::new(&code).map_err(serde::de::Error::custom)
+    }
+}
+
+
+
+/// Asset that is collected from rates endpoint.
+///
+/// `id` - asset id as used by endpoint, i.e. "bitcoin".
+/// `symbol` - symbol of the asset. If it is not configured, it is taken from
+/// endpoint response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    pub id: String,
+    pub symbol: Option<Symbol>,
+}
+
+
+
+/// Assets configured at startup.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    assets: Vec<Asset>,
+}
+
+
+
+impl Registry {
+    /// Parse comma separated asset list, where each asset is either `id` or
+    /// `id:SYMBOL`, i.e. "bitcoin:BTC,ethereum,solana:SOL".
+    ///
+    /// Duplicate asset ids are ignored.
+    pub fn parse(assets: &str) -> Result<Self, SymbolError> {
+        let mut registry = Self::default();
+
+        for def in assets.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            let (id, symbol) = match def.split_once(':') {
+                Some((id, symbol)) => (id.trim(), Some(Symbol::new(symbol)?)),
+                None => (def, None),
+            };
+
+            let id_valid = id.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+            if id.is_empty() || !id_valid {
+                return Err(SymbolError::AssetInvalid(def.to_string()))
+            }
+
+            if registry.assets.iter().any(|a| a.id == id) {
+                continue;
+            }
+
+            registry.assets.push(Asset {
+                id: id.to_string(),
+                symbol,
+            });
+        }
+
+        Ok(registry)
+    }
+
+
+
+    /// Load registry from ENV.
+    ///
+    /// `ASSETS` - asset list, see Registry::parse, defaults to bitcoin.
+    pub fn from_env() -> Result<Self, SymbolError> {
+        let assets = env::var("ASSETS").unwrap_or_else(|_| "bitcoin".to_string());
+
+        Self::parse(&assets)
+    }
+
+
+
+    pub fn assets(&self) -> &[Asset] {
+        &self.assets
+    }
+
+
+
+    /// Find configured asset by endpoint asset id.
+    pub fn asset_get(&self, id: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.id == id)
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_symbol_new() {
+        let btc = Symbol::new(" btc ").unwrap();
+        assert_eq!(btc.as_str(), "BTC");
+        assert_eq!(btc, Symbol::new("BTC").unwrap());
+        assert_eq!(Symbol::new("usd").unwrap(), Symbol::USD);
+        assert_eq!(format!("{}/{}", btc, Symbol::USD), "BTC/USD");
+
+        assert_eq!(Symbol::new(""), Err(SymbolError::Empty));
+        assert!(matches!(Symbol::new("BT C"), Err(SymbolError::InvalidChar(..))));
+        assert!(matches!(Symbol::new("ABCDEFGHIJKLMNOPQ"),
+            Err(SymbolError::TooLong(..))
+        ));
+    }
+
+    #[test]
+    fn test_symbol_serde() {
+        let sol: Symbol = serde_json::from_str("\"sol\"").unwrap();
+        assert_eq!(sol.as_str(), "SOL");
+        assert_eq!(serde_json::to_string(&sol).unwrap(), "\"SOL\"");
+        assert!(serde_json::from_str::<Symbol>("\"S/L\"").is_err());
+    }
+
+    #[test]
+    fn test_registry_parse() {
+        let registry = Registry::parse("bitcoin:btc, ethereum,,bitcoin").unwrap();
+
+        assert_eq!(registry.assets(), &[
+            Asset { id: "bitcoin".to_string(), symbol: Some(Symbol::new("BTC").unwrap()) },
+            Asset { id: "ethereum".to_string(), symbol: None },
+        ]);
+        assert!(registry.asset_get("solana").is_none());
+
+        assert!(Registry::parse("bit coin").is_err());
+        assert!(Registry::parse("bitcoin:").is_err());
+    }
+}
@@ -9,34 +9,49 @@ use std::{
 
 
 
-use tokio::{
-    time::sleep,
+use rate_limit::clock::{
+    Clock,
+    SystemClock,
 };
 
 use crate::{
     shared_state::SharedState,
     atomic_swap::AtomicSwap,
-    ohlc::Ohlc,
+    ohlc_calc::OhlcMap,
 };
 
 
 
 pub struct TerminalOutput {
-    terminal: Arc<AtomicSwap<Option<Ohlc>>>,
+    terminal: Arc<AtomicSwap<Option<OhlcMap>>>,
+    clock: Arc<dyn Clock>,
 }
 
 
 
 impl TerminalOutput {
-    pub fn new(terminal: Arc<AtomicSwap<Option<Ohlc>>>) -> Self {
+    pub fn new(terminal: Arc<AtomicSwap<Option<OhlcMap>>>) -> Self {
         Self {
             terminal,
+            clock: Arc::new(SystemClock),
         }
     }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
 }
 
 
 
+/// How often metrics are printed.
+const METRICS_PERIOD: Duration = Duration::from_secs(60);
+
+
+
 pub async fn main(term: TerminalOutput, shared_state: Arc<SharedState>) {
     // Terminal update is 1 per second as requested in specification.
     // TODO: we could make this configurabel from .env.
@@ -44,8 +59,13 @@ pub async fn main(term: TerminalOutput, shared_state: Arc<SharedState>) {
 
     let mut intr = shared_state.shut_down.load(Ordering::Relaxed);
 
-    let mut ohlc: Box<Option<Ohlc>> = Box::new(None);
-    let mut ohlc_display: Option<Ohlc> = None;
+    let mut ohlc: Box<Option<OhlcMap>> = Box::new(None);
+    let mut ohlc_display: Option<OhlcMap> = None;
+
+    // Output is scheduled on monotonic clock, so that time spent printing does
+    // not accumulate and wall clock adjustments do not skip or repeat output.
+    let mut deadline = term.clock.instant();
+    let mut deadline_metrics = deadline + METRICS_PERIOD;
 
     while intr == 0 {
         intr = shared_state.shut_down.load(Ordering::Relaxed);
@@ -56,16 +76,23 @@ pub async fn main(term: TerminalOutput, shared_state: Arc<SharedState>) {
         // if collector is retrieving data slower than 1 per sec or data
         // calculator can not calculate fast enough rate.
         if let Some(..) = *ohlc {
-            ohlc_display = *ohlc;
-            *ohlc = None;
+            ohlc_display = ohlc.take();
         }
 
-        if let Some(ref ohlc) = ohlc_display {
-            if ohlc.start != 0 {
-                println!("{:?}", ohlc);
+        if let Some(ref ohlcs) = ohlc_display {
+            for ohlc in ohlcs.values() {
+                println!("{}", ohlc);
             }
         }
-        sleep(sleep_duration).await;
+
+        if deadline >= deadline_metrics {
+            print!("{}", shared_state.metrics);
+            deadline_metrics = deadline + METRICS_PERIOD;
+        }
+
+        // If output has fallen behind, do not print missed updates in burst.
+        deadline = (deadline + sleep_duration).max(term.clock.instant());
+        term.clock.sleep_until(deadline).await;
     }
 }
 
@@ -0,0 +1,520 @@
+//! Validation of ticks before they reach OhlcCalc.
+//!
+//! OhlcCalc accepts any rate, so a single bogus value would permanently set
+//! high or low of a candle. Every tick passes validator first, it rejects:
+//! - ticks without rate or with zero rate,
+//! - ticks older than the last accepted tick of the same pair by more than
+//!   allowed lateness,
+//! - ticks newer than clock by more than `max_future` seconds, otherwise
+//!   single tick with bogus timestamp would make every later tick out of
+//!   order,
+//! - spikes, rates that move from median of recent accepted rates by more
+//!   than `max_move_percent`, or whose z-score against recent accepted rates
+//!   exceeds `max_zscore`.
+//!
+//! Rejected ticks are sent to quarantine sink together with the reason and
+//! are counted by `validator_rejected_total{pair="BTC/USD",reason="move"}`
+//! metric. If rate stays at new level for `reset_after` ticks in a row, that
+//! is every spike is within `max_move_percent` of the first one, it is
+//! considered a real move rather than a spike, recent rates are forgotten and
+//! the tick is accepted. Spikes that do not agree with each other stay
+//! rejected.
+
+
+
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    fmt,
+    sync::{
+        Arc,
+        atomic::Ordering,
+    },
+    time::UNIX_EPOCH,
+};
+
+use tokio::sync::mpsc;
+
+use rate_limit::{
+    clock::{
+        Clock,
+        SystemClock,
+    },
+    config::env_parse,
+};
+
+use crate::{
+    shared_state::SharedState,
+    price::Price,
+    price_info::PriceInfo,
+    symbol::Symbol,
+};
+
+
+
+/// Validation configuration.
+///
+/// `max_move_percent` - maximal move from median of recent rates, 0 disables
+/// the check.
+/// `max_zscore` - maximal z-score against recent rates, 0 disables the check.
+/// `window` - number of recent accepted rates that ticks are compared to.
+/// `min_samples` - z-score is checked only once that many rates are known.
+/// `reset_after` - number of spikes in a row after which rate is accepted as
+/// new level, 0 never accepts it.
+/// `lateness` - ticks that are older than the last accepted tick by up to that
+/// many seconds are not out of order, it should match lateness allowed by
+/// OhlcCalc.
+/// `max_future` - how many seconds tick timestamp may be ahead of clock, 0
+/// disables the check, i.e. for replayed ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidatorPolicy {
+    pub max_move_percent: f64,
+    pub max_zscore: f64,
+    pub window: usize,
+    pub min_samples: usize,
+    pub reset_after: u32,
+    pub lateness: u64,
+    pub max_future: u64,
+}
+
+
+
+impl Default for ValidatorPolicy {
+    fn default() -> Self {
+        Self {
+            max_move_percent: 10.0,
+            max_zscore: 0.0,
+            window: 20,
+            min_samples: 5,
+            reset_after: 5,
+            lateness: 0,
+            max_future: 60,
+        }
+    }
+}
+
+
+
+impl ValidatorPolicy {
+    /// Load policy from ENV, parameters that are not set keep default values.
+    ///
+    /// `VALIDATE_MAX_MOVE_PERCENT` - maximal move from recent rates.
+    /// `VALIDATE_MAX_ZSCORE` - maximal z-score against recent rates.
+    /// `VALIDATE_WINDOW` - number of recent rates.
+    /// `VALIDATE_MIN_SAMPLES` - rates needed before z-score is checked.
+    /// `VALIDATE_RESET_AFTER` - spikes in a row that are accepted as new level.
+    /// `VALIDATE_MAX_FUTURE_SECS` - how far tick may be ahead of clock.
+    pub fn from_env() -> Result<Self, String> {
+        let mut policy = Self::default();
+
+        env_parse("VALIDATE_MAX_MOVE_PERCENT", &mut policy.max_move_percent)?;
+        env_parse("VALIDATE_MAX_ZSCORE", &mut policy.max_zscore)?;
+        env_parse("VALIDATE_WINDOW", &mut policy.window)?;
+        env_parse("VALIDATE_MIN_SAMPLES", &mut policy.min_samples)?;
+        env_parse("VALIDATE_RESET_AFTER", &mut policy.reset_after)?;
+        env_parse("VALIDATE_MAX_FUTURE_SECS", &mut policy.max_future)?;
+
+        let valid = |v: f64| v >= 0.0 && v.is_finite();
+
+        if !valid(policy.max_move_percent) {
+            return Err("VALIDATE_MAX_MOVE_PERCENT must not be negative".to_string())
+        }
+
+        if !valid(policy.max_zscore) {
+            return Err("VALIDATE_MAX_ZSCORE must not be negative".to_string())
+        }
+
+        if policy.window == 0 {
+            return Err("VALIDATE_WINDOW must be positive".to_string())
+        }
+
+        if policy.min_samples < 2 || policy.min_samples > policy.window {
+            return Err(concat!("VALIDATE_MIN_SAMPLES must be at least 2 and not",
+                " greater than VALIDATE_WINDOW"
+            ).to_string())
+        }
+
+        Ok(policy)
+    }
+}
+
+
+
+/// Why tick was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    NoRate,
+    ZeroRate,
+    OutOfOrder,
+    Future,
+    Move,
+    ZScore,
+}
+
+
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRate => write!(f, "no_rate"),
+            Self::ZeroRate => write!(f, "zero_rate"),
+            Self::OutOfOrder => write!(f, "out_of_order"),
+            Self::Future => write!(f, "future"),
+            Self::Move => write!(f, "move"),
+            Self::ZScore => write!(f, "zscore"),
+        }
+    }
+}
+
+
+
+/// Tick that did not pass validation.
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub info: PriceInfo,
+    pub reason: Reason,
+}
+
+
+
+/// Formats as CSV row `timestamp,base,quote,rate,reason`, rate is empty if
+/// tick has none.
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = &self.info;
+        write!(f, "{},{},{},", info.timestamp, info.base, info.quote)?;
+
+        if let Some(rate) = info.rate {
+            write!(f, "{}", rate)?;
+        }
+
+        write!(f, ",{}", self.reason)
+    }
+}
+
+
+
+fn as_f64(rate: Price) -> f64 {
+    rate.value() as f64 / 10f64.powi(rate.scale() as i32)
+}
+
+
+
+// Whether rate has moved from `from` by more than `max_move_percent`, it
+// never has if the check is disabled.
+fn moved(from: f64, rate: f64, policy: &ValidatorPolicy) -> bool {
+    policy.max_move_percent > 0.0
+        && (rate - from).abs() * 100.0 > from * policy.max_move_percent
+}
+
+
+
+/// Validation state of single pair.
+///
+/// `timestamp` - timestamp of the newest accepted tick.
+/// `rates` - recent accepted rates.
+/// `spikes` - spikes in a row that agree with `level`.
+/// `level` - rate of the first of these spikes, possible new level.
+#[derive(Debug, Clone, Default)]
+struct Track {
+    timestamp: u64,
+    rates: VecDeque<f64>,
+    spikes: u32,
+    level: Option<f64>,
+}
+
+
+
+impl Track {
+    /// Check tick against state and clock time `now` in Unix seconds, if it
+    /// is accepted, it becomes part of the state.
+    fn check(&mut self, info: &PriceInfo, now: u64, policy: &ValidatorPolicy)
+        -> Result<(), Reason>
+    {
+        let Some(rate) = info.rate else {
+            return Err(Reason::NoRate)
+        };
+
+        if rate.value() == 0 {
+            return Err(Reason::ZeroRate)
+        }
+
+        if info.timestamp + policy.lateness < self.timestamp {
+            return Err(Reason::OutOfOrder)
+        }
+
+        if policy.max_future > 0 && info.timestamp > now + policy.max_future {
+            return Err(Reason::Future)
+        }
+
+        let rate = as_f64(rate);
+        if let Some(reason) = self.spike(rate, policy) {
+            // Spike that does not agree with previous ones starts new run.
+            match self.level {
+                Some(level) if !moved(level, rate, policy) => self.spikes += 1,
+                _ => {
+                    self.level = Some(rate);
+                    self.spikes = 1;
+                }
+            }
+
+            if policy.reset_after == 0 || self.spikes < policy.reset_after {
+                return Err(reason)
+            }
+
+            // Rate has stayed at new level long enough.
+            self.rates.clear();
+        }
+
+        self.spikes = 0;
+        self.level = None;
+        self.timestamp = self.timestamp.max(info.timestamp);
+
+        if self.rates.len() >= policy.window {
+            self.rates.pop_front();
+        }
+        self.rates.push_back(rate);
+
+        Ok(())
+    }
+
+
+
+    fn spike(&self, rate: f64, policy: &ValidatorPolicy) -> Option<Reason> {
+        if self.rates.is_empty() {
+            return None
+        }
+
+        if policy.max_move_percent > 0.0 {
+            let mut sorted: Vec<f64> = self.rates.iter().copied().collect();
+            sorted.sort_unstable_by(f64::total_cmp);
+
+            let mid = sorted.len() / 2;
+            let median = if sorted.len().is_multiple_of(2) {
+                (sorted[mid - 1] + sorted[mid]) / 2.0
+            }
+            else {
+                sorted[mid]
+            };
+
+            if moved(median, rate, policy) {
+                return Some(Reason::Move)
+            }
+        }
+
+        if policy.max_zscore > 0.0 && self.rates.len() >= policy.min_samples {
+            let n = self.rates.len() as f64;
+            let mean = self.rates.iter().sum::<f64>() / n;
+            let variance = self.rates.iter()
+                .map(|r| (r - mean) * (r - mean))
+                .sum::<f64>() / n;
+
+            // Flat rates have no deviation to compare to.
+            let deviation = variance.sqrt();
+            if deviation > 0.0
+                && (rate - mean).abs() > deviation * policy.max_zscore
+            {
+                return Some(Reason::ZScore)
+            }
+        }
+
+        None
+    }
+}
+
+
+
+/// Validates ticks between sources and OhlcCalc.
+///
+/// `rx` - ticks from sources.
+/// `tx` - accepted ticks are sent there.
+/// `tx_quarantine` - rejected ticks are sent there.
+/// `clock` - ticks from the future are checked against it.
+pub struct Validator {
+    rx: mpsc::Receiver<PriceInfo>,
+    tx: mpsc::Sender<PriceInfo>,
+    tx_quarantine: mpsc::Sender<Rejected>,
+    policy: ValidatorPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+
+
+impl Validator {
+    pub fn new(policy: ValidatorPolicy, rx: mpsc::Receiver<PriceInfo>,
+        tx: mpsc::Sender<PriceInfo>, tx_quarantine: mpsc::Sender<Rejected>
+    )
+        -> Self
+    {
+        Self {
+            rx, tx, tx_quarantine, policy,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+
+
+    /// Set source of time, by default system clock is used.
+    pub fn clock_set(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+}
+
+
+
+pub async fn main(mut validator: Validator, shared_state: Arc<SharedState>) {
+    let mut tracks: HashMap<(Symbol, Symbol), Track> = HashMap::new();
+
+    // If collector thread has crashed, this thread has no use to be alive.
+    while let Some(info) = validator.rx.recv().await {
+        let track = tracks.entry((info.base, info.quote)).or_default();
+        let now = validator.clock.now().duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        match track.check(&info, now, &validator.policy) {
+            // Calc is not ahead of validator, so accepted ticks wait for it,
+            // sources decide whether to drop data if it can not keep up.
+            Ok(()) => if validator.tx.send(info).await.is_err() {
+                break
+            },
+
+            Err(reason) => {
+                shared_state.metrics.get(&format!(
+                    "validator_rejected_total{{pair=\"{}/{}\",reason=\"{}\"}}",
+                    info.base, info.quote, reason
+                )).fetch_add(1, Ordering::Relaxed);
+
+                let rejected = Rejected { info, reason };
+                if validator.tx_quarantine.try_send(rejected).is_err() {
+                    eprintln!(concat!("WARNING: quarantine can not keep up,",
+                        " dropping rejected tick."
+                    ));
+                }
+            }
+        }
+
+        let intr = shared_state.shut_down.load(Ordering::Relaxed);
+        if intr != 0 {
+            break
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tick(timestamp: u64, rate: u64) -> PriceInfo {
+        let btc = Symbol::new("BTC").unwrap();
+        let rate = Price::new(rate, 2).unwrap();
+
+        PriceInfo::new(timestamp, btc, Symbol::USD, Some(rate))
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = ValidatorPolicy {
+            max_zscore: 2.0,
+            reset_after: 3,
+            ..ValidatorPolicy::default()
+        };
+        let mut track = Track::default();
+        let mut check = |ts, rate| track.check(&tick(ts, rate), 100, &policy);
+
+        assert_eq!(check(10, 10000), Ok(()));
+        assert_eq!(check(10, 0), Err(Reason::ZeroRate));
+        assert_eq!(check(9, 10000), Err(Reason::OutOfOrder));
+
+        // Move from median by more than 10% is a spike.
+        assert_eq!(check(11, 11500), Err(Reason::Move));
+        assert_eq!(check(11, 10800), Ok(()));
+
+        // Small moves outside of usual deviation are spikes by z-score.
+        for (ts, rate) in [(12, 10100), (13, 10700), (14, 10300), (15, 10500)] {
+            assert_eq!(check(ts, rate), Ok(()));
+        }
+        assert_eq!(check(16, 10000), Ok(()));
+        assert_eq!(check(17, 9600), Err(Reason::ZScore));
+        assert_eq!(check(17, 10300), Ok(()));
+
+        // Rate that stays at new level is accepted eventually.
+        assert_eq!(check(18, 13000), Err(Reason::Move));
+        assert_eq!(check(19, 13000), Err(Reason::Move));
+        assert_eq!(check(20, 13000), Ok(()));
+        assert_eq!(check(21, 13100), Ok(()));
+
+        // Tick within allowed lateness is not out of order.
+        let policy = ValidatorPolicy {
+            lateness: 5,
+            ..policy
+        };
+        assert_eq!(track.check(&tick(16, 13000), 100, &policy), Ok(()));
+        assert_eq!(track.check(&tick(15, 13000), 100, &policy), Err(Reason::OutOfOrder));
+
+        // Spikes that do not agree with each other never become new level.
+        for (ts, rate) in [(22, 18000), (23, 8000), (24, 19000), (25, 9000),
+            (26, 17000), (27, 8500)
+        ] {
+            assert_eq!(track.check(&tick(ts, rate), 100, &policy),
+                Err(Reason::Move)
+            );
+        }
+        assert_eq!(track.check(&tick(28, 13200), 100, &policy), Ok(()));
+
+        let mut none = tick(22, 1);
+        none.rate = None;
+        assert_eq!(track.check(&none, 100, &policy), Err(Reason::NoRate));
+
+        // Tick far ahead of clock is rejected and does not move timestamp of
+        // the pair, so next ticks are not out of order.
+        assert_eq!(track.check(&tick(161, 13200), 100, &policy),
+            Err(Reason::Future)
+        );
+        assert_eq!(track.check(&tick(29, 13200), 100, &policy), Ok(()));
+        assert_eq!(track.check(&tick(160, 13200), 100, &policy), Ok(()));
+
+        let policy = ValidatorPolicy {
+            max_future: 0,
+            ..policy
+        };
+        assert_eq!(track.check(&tick(10000, 13200), 100, &policy), Ok(()));
+    }
+
+    /// Accepted ticks reach calc, rejected ones reach quarantine with reason.
+    #[tokio::test]
+    async fn test_validator() {
+        let (tx, rx) = mpsc::channel(10);
+        let (tx_valid, mut rx_valid) = mpsc::channel(10);
+        let (tx_quarantine, mut rx_quarantine) = mpsc::channel(10);
+        let state = Arc::new(SharedState::default());
+
+        let validator = Validator::new(ValidatorPolicy::default(), rx, tx_valid,
+            tx_quarantine
+        );
+        let h = tokio::spawn(main(validator, state.clone()));
+
+        for info in [tick(10, 10000), tick(11, 50000), tick(12, 10100)] {
+            tx.send(info).await.unwrap();
+        }
+        drop(tx);
+        h.await.unwrap();
+
+        let mut valid = Vec::new();
+        while let Some(info) = rx_valid.recv().await {
+            valid.push(info.rate.unwrap().value());
+        }
+        assert_eq!(valid, vec![10000, 10100]);
+
+        let rejected = rx_quarantine.recv().await.unwrap();
+        assert_eq!(rejected.to_string(), "11,BTC,USD,500.00,move");
+        assert!(rx_quarantine.recv().await.is_none());
+
+        let rejected = state.metrics
+            .get("validator_rejected_total{pair=\"BTC/USD\",reason=\"move\"}");
+        assert_eq!(rejected.load(Ordering::Relaxed), 1);
+    }
+}